@@ -0,0 +1,510 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::instruction::{Arg, ENCODINGS};
+
+/// a place in the assembly sources
+#[derive(Debug,Clone,PartialEq)]
+pub struct Position {
+    pub file: String,
+    pub line: usize,
+    pub column: usize
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct AsmError {
+    pub at: Position,
+    pub message: String
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.at, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+fn error<T>(at: &Position, message: String) -> Result<T, AsmError> {
+    Err(AsmError { at: at.clone(), message })
+}
+
+/// an operand of an instruction with its expression evaluated
+#[derive(Debug,Clone,PartialEq)]
+pub enum Operand {
+    Register(u8),
+    Word(String), // I, [I], DT, ST, K, F, HF, B or R
+    Value(i64),
+    Long(i64)     // `LONG expr`
+}
+
+/// why an instruction cannot be encoded, and which operand is to blame if one is
+#[derive(Debug,Clone,PartialEq)]
+pub struct EncodeError {
+    pub operand: Option<usize>,
+    pub message: String
+}
+
+const WORDS: [&str; 9] = ["I", "[I]", "DT", "ST", "K", "F", "HF", "B", "R"];
+
+fn fits(arg: &Arg, operand: &Operand) -> bool {
+    match (arg, operand) {
+        (Arg::X, Operand::Register(_)) | (Arg::Y, Operand::Register(_)) => true,
+        (Arg::Word("V0"), Operand::Register(0)) => true,
+        (Arg::Word(w), Operand::Word(o)) => w.eq_ignore_ascii_case(o),
+        (Arg::Byte, Operand::Value(_)) | (Arg::Nibble, Operand::Value(_)) | (Arg::Addr, Operand::Value(_)) | (Arg::Planes, Operand::Value(_)) => true,
+        (Arg::Long, Operand::Long(_)) => true,
+        _ => false
+    }
+}
+
+/// the bytes of `mnemonic` with `operands`, using the table the decoder uses
+pub fn encode(mnemonic: &str, operands: &[Operand]) -> Result<Vec<u8>, EncodeError> {
+    let fail = |operand, message| Err(EncodeError { operand, message });
+    let forms: Vec<_> = ENCODINGS.iter().filter(|e| e.mnemonic.eq_ignore_ascii_case(mnemonic)).collect();
+    if forms.is_empty() {
+        return fail(None, format!("unknown instruction {}", mnemonic))
+    }
+    let e = match forms.iter().find(|e| e.args.len() == operands.len() && e.args.iter().zip(operands).all(|(a, o)| fits(a, o))) {
+        Some(e) => e,
+        None => return fail(None, format!("{} does not take these operands", mnemonic.to_uppercase()))
+    };
+    let mut opcode = e.bits;
+    let mut long = None;
+    for (n, (arg, operand)) in e.args.iter().zip(operands).enumerate() {
+        let value = match operand {
+            _ if matches!(arg, Arg::Word(_)) => continue, // JP V0, addr
+            Operand::Register(r) => *r as i64,
+            Operand::Value(v) if *arg == Arg::Byte && (-128..0).contains(v) => v & 0xff, // ADD V0, -1
+            Operand::Value(v) => *v,
+            Operand::Long(v) => {
+                if !(0..=0xffff).contains(v) { return fail(Some(n), format!("{:#x} is not a 16-bit address", v)) }
+                long = Some(*v as u16);
+                continue
+            },
+            Operand::Word(_) => continue
+        };
+        opcode = match u16::try_from(value).ok().and_then(|v| arg.put(opcode, v)) {
+            Some(opcode) => opcode,
+            None => return fail(Some(n), format!("{} does not fit into {}", value, match arg {
+                Arg::Byte => "a byte",
+                Arg::Nibble => "a nibble",
+                Arg::Addr => "12 bits, use LD I, LONG for addresses above 0xfff",
+                Arg::Planes => "the plane mask 0-3",
+                _ => "the instruction"
+            }))
+        }
+    }
+    let mut bytes = opcode.to_be_bytes().to_vec();
+    bytes.extend(long.map(|l| l.to_be_bytes()).iter().flatten());
+    Ok(bytes)
+}
+
+/// a ROM assembled from source, with the value of every label and constant
+#[derive(Debug,Clone,PartialEq)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    pub symbols: BTreeMap<String, i64>
+}
+
+impl Assembly {
+    /// a `VALUE NAME` line per symbol, ordered by value
+    pub fn symbol_map(&self) -> String {
+        let mut symbols: Vec<(&String, &i64)> = self.symbols.iter().collect();
+        symbols.sort_by_key(|(name, value)| (**value, (*name).clone()));
+        symbols.iter().map(|(name, value)| format!("{:#06x} {}\n", value, name)).collect()
+    }
+}
+
+/// assembles the file at `path`, whose includes are relative to it
+pub fn assemble_file(path: &str) -> Result<Assembly, AsmError> {
+    let text = fs::read_to_string(path).map_err(|e| AsmError { at: Position { file: path.to_string(), line: 0, column: 0 }, message: e.to_string() })?;
+    assemble_from(&text, path)
+}
+
+/// assembles `source`, which calls itself `name` in errors; includes are relative to the working directory
+pub fn assemble(source: &str, name: &str) -> Result<Assembly, AsmError> {
+    let mut a = Assembler::new();
+    a.load(source, name, Path::new(""))?;
+    a.finish()
+}
+
+fn assemble_from(source: &str, path: &str) -> Result<Assembly, AsmError> {
+    let mut a = Assembler::new();
+    a.including.push(PathBuf::from(path));
+    a.load(source, path, Path::new(path).parent().unwrap_or(Path::new("")))?;
+    a.finish()
+}
+
+/// a piece of source text and where it starts
+#[derive(Debug,Clone)]
+struct Text {
+    text: String,
+    at: Position
+}
+
+enum Body {
+    Instruction(Text, Vec<Text>), // mnemonic, operands
+    Data(u16, Vec<Text>)          // width in bytes, items
+}
+
+struct Statement {
+    address: u16,
+    body: Body
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    labels: BTreeMap<String, (i64, Position)>,
+    constants: BTreeMap<String, (Text, u16)>, // expression, and the address it was defined at
+    here: u32,
+    including: Vec<PathBuf>
+}
+
+impl Assembler {
+    const ORIGIN: u16 = 0x200;
+
+    fn new() -> Self {
+        Self { statements: Vec::new(), labels: BTreeMap::new(), constants: BTreeMap::new(), here: Self::ORIGIN as u32, including: Vec::new() }
+    }
+
+    /// first pass: splits the lines, gives every label its address and reads the includes
+    fn load(&mut self, source: &str, file: &str, dir: &Path) -> Result<(), AsmError> {
+        for (n, raw) in source.lines().enumerate() {
+            let at = |column| Position { file: file.to_string(), line: n + 1, column };
+            let code = strip_comment(raw);
+            let mut rest = Text { text: code.to_string(), at: at(1) }.trim();
+
+            if let Some((label, after)) = rest.split_label() {
+                self.define(&label.text, &label.at, self.here as i64)?;
+                rest = after.trim();
+            }
+            if rest.text.is_empty() { continue }
+
+            let (word, after) = rest.split_word();
+            let after = after.trim();
+            if after.text.starts_with('=') || after.split_word().0.text.eq_ignore_ascii_case("equ") {
+                let expr = if after.text.starts_with('=') { after.skip(1) } else { after.split_word().1 }.trim();
+                if !is_identifier(&word.text) { return error(&word.at, format!("{} is not a name", word.text)) }
+                if expr.text.is_empty() { return error(&expr.at, format!("{} needs a value", word.text)) }
+                self.check_new(&word.text, &word.at)?;
+                self.constants.insert(word.text.clone(), (expr, self.here as u16));
+                continue
+            }
+
+            let size = match word.text.to_lowercase().as_str() {
+                "include" => {
+                    let name = match after.string()? {
+                        Some(name) if !name.is_empty() => name,
+                        _ => return error(&after.at, "include needs a \"file name\"".to_string())
+                    };
+                    let path = dir.join(name);
+                    if self.including.contains(&path) { return error(&after.at, format!("{} includes itself", path.display())) }
+                    let text = fs::read_to_string(&path).map_err(|e| AsmError { at: after.at.clone(), message: format!("cannot include {}: {}", path.display(), e) })?;
+                    self.including.push(path.clone());
+                    self.load(&text, &path.display().to_string(), path.parent().unwrap_or(Path::new("")))?;
+                    self.including.pop();
+                    continue
+                },
+                "db" | "dw" => {
+                    let width = if word.text.eq_ignore_ascii_case("db") { 1 } else { 2 };
+                    let items = after.split_operands();
+                    if items.is_empty() { return error(&after.at, format!("{} needs values", word.text)) }
+                    let mut size = 0;
+                    for item in &items {
+                        size += match item.string()? {
+                            Some(bytes) if width == 1 => bytes.len() as u32,
+                            _ => width as u32
+                        }
+                    }
+                    self.statements.push(Statement { address: self.here as u16, body: Body::Data(width, items) });
+                    size
+                },
+                _ => {
+                    let operands = after.split_operands();
+                    let long = operands.iter().any(|o| o.split_word().0.text.eq_ignore_ascii_case("long"));
+                    self.statements.push(Statement { address: self.here as u16, body: Body::Instruction(word, operands) });
+                    if long { 4 } else { 2 }
+                }
+            };
+            self.here += size;
+            if self.here > 0x10000 { return error(&at(1), "the program does not fit into 64 KiB of memory".to_string()) }
+        }
+        Ok(())
+    }
+
+    fn check_new(&self, name: &str, at: &Position) -> Result<(), AsmError> {
+        if let Some((_, first)) = self.labels.get(name) {
+            return error(at, format!("{} is already defined at {}", name, first))
+        }
+        if let Some((expr, _)) = self.constants.get(name) {
+            return error(at, format!("{} is already defined at {}", name, expr.at))
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, at: &Position, value: i64) -> Result<(), AsmError> {
+        if !is_identifier(name) { return error(at, format!("{} is not a name", name)) }
+        self.check_new(name, at)?;
+        self.labels.insert(name.to_string(), (value, at.clone()));
+        Ok(())
+    }
+
+    /// second pass: evaluates the operands and encodes the instructions
+    fn finish(self) -> Result<Assembly, AsmError> {
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            match &statement.body {
+                Body::Data(width, items) => for item in items {
+                    if let (1, Some(bytes)) = (*width, item.string()?) {
+                        rom.extend(bytes.bytes());
+                        continue
+                    }
+                    let value = self.eval(item, statement.address, &mut Vec::new())?;
+                    let limit = if *width == 1 { 0xff } else { 0xffff };
+                    if value < -(limit + 1) / 2 || value > limit {
+                        return error(&item.at, format!("{} does not fit into {}", value, if *width == 1 { "a byte" } else { "a word" }))
+                    }
+                    let bytes = (value as u16).to_be_bytes();
+                    rom.extend_from_slice(&bytes[2 - *width as usize..])
+                },
+                Body::Instruction(mnemonic, texts) => {
+                    let mut operands = Vec::new();
+                    for text in texts {
+                        operands.push(self.operand(text, statement.address)?)
+                    }
+                    match encode(&mnemonic.text, &operands) {
+                        Ok(bytes) => rom.extend(bytes),
+                        Err(e) => return error(e.operand.map(|n| &texts[n].at).unwrap_or(&mnemonic.at), e.message)
+                    }
+                }
+            }
+        }
+        let mut symbols: BTreeMap<String, i64> = self.labels.iter().map(|(name, (value, _))| (name.clone(), *value)).collect();
+        for (name, (expr, address)) in &self.constants {
+            symbols.insert(name.clone(), self.eval(expr, *address, &mut Vec::new())?);
+        }
+        Ok(Assembly { rom, symbols })
+    }
+
+    fn operand(&self, text: &Text, here: u16) -> Result<Operand, AsmError> {
+        let t = text.text.as_str();
+        if t.len() == 2 && (t.starts_with('V') || t.starts_with('v')) {
+            if let Ok(r) = u8::from_str_radix(&t[1..], 16) { return Ok(Operand::Register(r)) }
+        }
+        if let Some(w) = WORDS.iter().find(|w| w.eq_ignore_ascii_case(t)) {
+            return Ok(Operand::Word(w.to_string()))
+        }
+        let (first, rest) = text.split_word();
+        if first.text.eq_ignore_ascii_case("long") {
+            return Ok(Operand::Long(self.eval(&rest.trim(), here, &mut Vec::new())?))
+        }
+        Ok(Operand::Value(self.eval(text, here, &mut Vec::new())?))
+    }
+
+    fn eval(&self, text: &Text, here: u16, visiting: &mut Vec<String>) -> Result<i64, AsmError> {
+        let mut parser = Parser { chars: text.text.chars().collect(), pos: 0, at: text.at.clone(), here, asm: self, visiting };
+        let value = parser.expr(0)?;
+        parser.skip_space();
+        if parser.pos < parser.chars.len() {
+            return error(&parser.position(), format!("unexpected {}", parser.chars[parser.pos]))
+        }
+        Ok(value)
+    }
+
+    fn symbol(&self, name: &str, at: &Position, visiting: &mut Vec<String>) -> Result<i64, AsmError> {
+        if let Some((value, _)) = self.labels.get(name) { return Ok(*value) }
+        match self.constants.get(name) {
+            Some(_) if visiting.iter().any(|v| v == name) => error(at, format!("{} is defined in terms of itself", name)),
+            Some((expr, address)) => {
+                visiting.push(name.to_string());
+                let value = self.eval(expr, *address, visiting);
+                visiting.pop();
+                value
+            },
+            None => error(at, format!("undefined symbol {}", name))
+        }
+    }
+}
+
+impl Text {
+    /// the characters between the quotes if this is a "string"
+    fn string(&self) -> Result<Option<&str>, AsmError> {
+        if !self.text.starts_with('"') { return Ok(None) }
+        match self.text[1..].find('"') {
+            Some(end) if end + 2 == self.text.len() => Ok(Some(&self.text[1..=end])),
+            Some(end) => error(&Position { column: self.at.column + end + 2, ..self.at.clone() }, "unexpected text after the string".to_string()),
+            None => error(&self.at, "unterminated string".to_string())
+        }
+    }
+
+    fn skip(&self, n: usize) -> Text {
+        let cut = self.text.char_indices().nth(n).map(|(i, _)| i).unwrap_or(self.text.len());
+        Text { text: self.text[cut..].to_string(), at: Position { column: self.at.column + n, ..self.at.clone() } }
+    }
+
+    fn trim(&self) -> Text {
+        let leading = self.text.chars().take_while(|c| c.is_whitespace()).count();
+        let t = self.skip(leading);
+        Text { text: t.text.trim_end().to_string(), at: t.at }
+    }
+
+    /// `name:` at the start
+    fn split_label(&self) -> Option<(Text, Text)> {
+        let (word, rest) = self.split_word();
+        let name = word.text.strip_suffix(':')?;
+        Some((Text { text: name.to_string(), at: word.at }, rest))
+    }
+
+    /// the text up to the first space, and the rest
+    fn split_word(&self) -> (Text, Text) {
+        let n = self.text.chars().take_while(|c| !c.is_whitespace()).count();
+        let word = self.text.chars().take(n).collect();
+        (Text { text: word, at: self.at.clone() }, self.skip(n))
+    }
+
+    /// comma separated operands, commas in strings and parentheses do not count
+    fn split_operands(&self) -> Vec<Text> {
+        if self.text.trim().is_empty() { return Vec::new() }
+        let mut out = Vec::new();
+        let (mut start, mut depth, mut quoted) = (0, 0, false);
+        let chars: Vec<char> = self.text.chars().collect();
+        for (n, c) in chars.iter().enumerate() {
+            match c {
+                '"' => quoted = !quoted,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => depth -= 1,
+                ',' if !quoted && depth == 0 => {
+                    out.push(Text { text: chars[start..n].iter().collect(), at: Position { column: self.at.column + start, ..self.at.clone() } }.trim());
+                    start = n + 1
+                },
+                _ => {}
+            }
+        }
+        out.push(self.skip(start).trim());
+        out
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (n, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..n],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false) && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// evaluates expressions with C's operators and precedence (no comparisons), `$` is the current address
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    at: Position,
+    here: u16,
+    asm: &'a Assembler,
+    visiting: &'a mut Vec<String>
+}
+
+const BINARY: [(&str, u8); 11] = [("|", 1), ("^", 2), ("&", 3), ("<<", 4), (">>", 4), ("+", 5), ("-", 5), ("*", 6), ("/", 6), ("%", 6), ("", 0)];
+
+impl Parser<'_> {
+    fn position(&self) -> Position {
+        Position { column: self.at.column + self.pos, ..self.at.clone() }
+    }
+
+    fn skip_space(&mut self) {
+        while self.chars.get(self.pos).map(|c| c.is_whitespace()).unwrap_or(false) { self.pos += 1 }
+    }
+
+    fn peek_operator(&mut self) -> Option<(&'static str, u8)> {
+        self.skip_space();
+        let rest: String = self.chars[self.pos..].iter().take(2).collect();
+        BINARY.iter().find(|(op, _)| !op.is_empty() && rest.starts_with(op)).copied()
+    }
+
+    /// operators that bind tighter than `min`
+    fn expr(&mut self, min: u8) -> Result<i64, AsmError> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.peek_operator() {
+            if precedence <= min { break }
+            let at = self.position();
+            self.pos += op.len();
+            let right = self.expr(precedence)?;
+            left = match op {
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "<<" => left.checked_shl(right as u32).unwrap_or(0),
+                ">>" => left.checked_shr(right as u32).unwrap_or(0),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                _ if right == 0 => return error(&at, "division by zero".to_string()),
+                "/" => left / right,
+                _ => left % right
+            }
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i64, AsmError> {
+        self.skip_space();
+        let at = self.position();
+        match self.chars.get(self.pos) {
+            Some('-') => { self.pos += 1; Ok(-self.unary()?) },
+            Some('+') => { self.pos += 1; self.unary() },
+            Some('~') => { self.pos += 1; Ok(!self.unary()?) },
+            Some('(') => {
+                self.pos += 1;
+                let value = self.expr(0)?;
+                self.skip_space();
+                if self.chars.get(self.pos) != Some(&')') { return error(&self.position(), "missing )".to_string()) }
+                self.pos += 1;
+                Ok(value)
+            },
+            Some('$') => { self.pos += 1; Ok(self.here as i64) },
+            Some('\'') if self.chars.get(self.pos + 2) == Some(&'\'') => {
+                self.pos += 3;
+                Ok(self.chars[self.pos - 2] as i64)
+            },
+            Some(c) if c.is_ascii_digit() => {
+                let word = self.word();
+                let parsed = match word.get(..2) {
+                    Some("0x") | Some("0X") => i64::from_str_radix(&word[2..], 16),
+                    Some("0b") | Some("0B") => i64::from_str_radix(&word[2..], 2),
+                    _ => word.parse()
+                };
+                parsed.or_else(|_| error(&at, format!("{} is not a number", word)))
+            },
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                let name = self.word();
+                self.asm.symbol(&name, &at, self.visiting)
+            },
+            Some(c) => error(&at, format!("unexpected {}", c)),
+            None => error(&at, "missing value".to_string())
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).map(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.').unwrap_or(false) { self.pos += 1 }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{prelude::*, SeekFrom};
+use std::fs::File;
+
+/// tone generator that sounds while the sound timer is non-zero
+pub struct Beeper {
+    pub volume: f32,    // 0.0 (silent) -- 1.0
+    pub frequency: f32, // of the square wave in Hz
+    sample_rate: u32,
+    phase: f32          // position within the current period (or XO-CHIP pattern), 0.0 -- 1.0
+}
+
+impl Beeper {
+    pub const SAMPLE_RATE: u32 = 44_100;
+    pub const FRAME_RATE: u32 = 60;
+
+    pub fn new(volume: f32, frequency: f32, sample_rate: u32) -> Self {
+        Self { volume, frequency, sample_rate, phase: 0.0 }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// samples of one 60 Hz frame, either a square wave or the looped 128-bit XO-CHIP `pattern` played at `pitch`
+    pub fn render_frame(&mut self, sounding: bool, pattern: Option<(&[u8; 16], u8)>) -> Vec<f32> {
+        let n = (self.sample_rate / Self::FRAME_RATE) as usize;
+        if !sounding {
+            self.phase = 0.0;
+            return vec![0.0; n]
+        }
+
+        let (rate, bits) = match pattern {
+            // Octo plays the pattern at 4000*2^((pitch-64)/48) bits per second
+            Some((_, pitch)) => (4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0) / 128.0, 128),
+            None => (self.frequency, 2)
+        };
+        let step = rate / self.sample_rate as f32;
+
+        let mut samples = Vec::with_capacity(n);
+        for _ in 0..n {
+            let bit = (self.phase * bits as f32) as usize;
+            let high = match pattern {
+                Some((buffer, _)) => (buffer[bit / 8] >> (7 - bit % 8)) & 0x01 == 0x01,
+                None => bit == 0
+            };
+            samples.push(if high { self.volume } else { -self.volume });
+            self.phase = (self.phase + step).fract();
+        }
+        samples
+    }
+}
+
+/// writes mono 16-bit PCM samples to a .wav file
+pub struct WavWriter {
+    file: File,
+    samples: u32
+}
+
+impl WavWriter {
+    pub fn create(filename: &str, sample_rate: u32) -> std::io::Result<Self> {
+        let mut file = File::create(filename)?;
+        file.write_all(b"RIFF\0\0\0\0WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&1u16.to_le_bytes())?; // mono
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(2*sample_rate).to_le_bytes())?; // bytes per second
+        file.write_all(&2u16.to_le_bytes())?; // bytes per sample
+        file.write_all(&16u16.to_le_bytes())?; // bits per sample
+        file.write_all(b"data\0\0\0\0")?;
+        Ok(Self { file, samples: 0 })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        let mut bytes = Vec::with_capacity(2*samples.len());
+        for s in samples {
+            bytes.extend_from_slice(&((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+        }
+        self.samples += samples.len() as u32;
+        self.file.write_all(&bytes)
+    }
+
+    /// fills in the chunk sizes, which are unknown until all samples are written
+    pub fn finish(mut self) -> std::io::Result<()> {
+        let data_size = 2*self.samples;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// plays samples on the default sound card
+#[cfg(feature = "audio")]
+pub struct AudioOutput {
+    queue: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<f32>>>,
+    _stream: cpal::Stream
+}
+
+#[cfg(feature = "audio")]
+impl AudioOutput {
+    pub fn new(sample_rate: u32) -> Result<Self, String> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use std::sync::{Arc, Mutex};
+        use std::collections::VecDeque;
+
+        let device = cpal::default_host().default_output_device().ok_or("no audio output device")?;
+        let config = cpal::StreamConfig { channels: 1, sample_rate: cpal::SampleRate(sample_rate), buffer_size: cpal::BufferSize::Default };
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let source = queue.clone();
+        let stream = device.build_output_stream(&config,
+            move |data: &mut [f32], _| {
+                let mut source = source.lock().unwrap();
+                data.iter_mut().for_each(|s| *s = source.pop_front().unwrap_or(0.0));
+            },
+            |e| eprintln!("audio stream error: {}", e), None).map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+        Ok(Self { queue, _stream: stream })
+    }
+
+    pub fn push(&self, samples: &[f32]) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() > 4*samples.len() { queue.clear() } // don't lag behind the emulation
+        queue.extend(samples)
+    }
+}
@@ -1,46 +1,267 @@
 
 use std::io::{prelude::*, Error, ErrorKind};
 use std::fs::File;
+use std::fmt;
+use std::cell::RefCell;
 
+use crate::font::Font;
+use crate::octo;
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+
+/// an access outside of the emulated memory
+#[derive(Debug,Clone,PartialEq)]
+pub struct MemoryError {
+    pub address: u16
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "memory address {:#06x} is out of range", self.address)
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+/// kind of memory access a watchpoint reacts to
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute")
+        }
+    }
+}
+
+/// addresses `start` to `end` (inclusive) to watch for the selected kinds of access
+#[derive(Debug,Clone,PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub pause: bool // stop in the debugger instead of only logging the access
+}
+
+impl Watchpoint {
+    /// `[r][w][x]:ADDR[-END]`, e.g. `w:0x300-0x30f` or `rwx:0x2a4`; without a prefix writes are watched
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (kinds, range) = text.split_once(':').unwrap_or(("w", text));
+        if kinds.is_empty() || !kinds.chars().all(|c| "rwx".contains(c)) {
+            return Err(format!("{} is not a combination of r, w and x", kinds))
+        }
+        let address = |a: &str| match a.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => a.parse()
+        }.map_err(|_| format!("{} is not an address", a));
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (address(start)?, address(end)?),
+            None => (address(range)?, address(range)?)
+        };
+        if end < start {
+            return Err(format!("{} ends before it starts", range))
+        }
+        Ok(Self { start, end, read: kinds.contains('r'), write: kinds.contains('w'), execute: kinds.contains('x'), pause: true })
+    }
+
+    fn watches(&self, address: u16, access: Access) -> bool {
+        (self.start..=self.end).contains(&address) && match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kinds: String = [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')].iter().filter(|(on, _)| *on).map(|(_, c)| *c).collect();
+        write!(f, "{}:{:#05x}", kinds, self.start)?;
+        if self.end != self.start { write!(f, "-{:#05x}", self.end)? }
+        if !self.pause { write!(f, " (log)")? }
+        Ok(())
+    }
+}
+
+/// a watched access; the program counter is filled in by `Chip8State`, which knows the instruction
+#[derive(Debug,Clone,PartialEq)]
+pub struct WatchHit {
+    pub watchpoint: usize,
+    pub pc: u16,
+    pub address: u16,
+    pub access: Access,
+    pub old: u8,
+    pub new: u8
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "watchpoint {}: pc:{:#06x} {} {:#06x}", self.watchpoint, self.pc, self.access, self.address)?;
+        match self.access {
+            Access::Write => write!(f, " {:02x} -> {:02x}", self.old, self.new),
+            _ => write!(f, " {:02x}", self.new)
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Cartridge {
-    pub memory: [u8; 0xf00],
-    fin: u16
+    pub memory: Vec<u8>,
+    fin: usize,
+    font_start: u16,
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    hits: RefCell<Vec<WatchHit>> // reads are recorded through &self
 }
 
 impl Cartridge {
     const CARTRIDGE_START: u16 = 0x0200;
+    pub const MEMORY_SIZE: usize = 0x1000;
+    pub const XO_CHIP_MEMORY_SIZE: usize = 0x10000;
 
-    pub fn new(filename: String) -> Self {
-        let mut file = File::open(&filename).expect("file opened");
+    /// loads the program in `filename`, compiling it first if it is Octo source (`.8o`)
+    pub fn new(filename: String, memory_size: usize) -> Result<Self, Error> {
+        if filename.ends_with(".8o") {
+            let assembly = octo::compile_file(&filename).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            return Self::from_rom(&assembly.rom, memory_size)
+        }
+        let mut file = File::open(&filename)?;
+        let mut rom = Vec::new();
+        file.read_to_end(&mut rom)?;
+        Self::from_rom(&rom, memory_size)
+    }
+
+    /// loads the program from `rom` instead of a file, rejecting programs that do not fit into memory
+    pub fn from_rom(rom: &[u8], memory_size: usize) -> Result<Self, Error> {
+        let room = memory_size - Self::CARTRIDGE_START as usize;
+        if rom.len() > room {
+            return Err(Error::new(ErrorKind::InvalidData, format!("program of {} bytes does not fit into the {} bytes from {:#05x} to the end of memory", rom.len(), room, Self::CARTRIDGE_START)))
+        }
+        let mut x = Self { memory: vec![0; memory_size], fin: 0, font_start: 0, watchpoints: Vec::new(), hits: RefCell::new(Vec::new()) };
+        x.load_font(&Font::default(), 0).expect("default font fits into memory");
+        let start = Self::CARTRIDGE_START as usize;
+        x.memory[start..start + rom.len()].copy_from_slice(rom);
+        x.fin = start + rom.len();
+        Ok(x)
+    }
+
+    /// replaces the built-in font; the SUPER-CHIP big digits follow the small ones
+    pub fn load_font(&mut self, font: &Font, start: u16) -> Result<(), String> {
+        let at = start as usize;
+        if at + Font::LEN > self.memory.len() {
+            return Err(format!("the font at {:#05x} does not fit into the {} bytes of memory", start, self.memory.len()))
+        }
+        if at < self.fin && at + Font::LEN > Self::CARTRIDGE_START as usize {
+            return Err(format!("the font at {:#05x}-{:#05x} overlaps the program at {:#05x}-{:#05x}",
+                start, at + Font::LEN - 1, Self::CARTRIDGE_START, self.fin - 1))
+        }
+        self.memory[at..at+font.small.len()].copy_from_slice(&font.small);
+        self.memory[at+font.small.len()..at+Font::LEN].copy_from_slice(&font.big);
+        self.font_start = start;
+        Ok(())
+    }
 
-        let mut x = Self { memory: [0; 0xf00], fin: 0 };
-        let mut fonts = File::open("fonts").expect("font file opened");
-        fonts.read(&mut x.memory).expect("font file read");
-        x.fin = Self::CARTRIDGE_START + file.read(&mut x.memory[0x200..]).expect("file read") as u16;
-        x
+    /// address of the 4x5 sprite of hex digit `digit`
+    pub fn font_address(&self, digit: u8) -> u16 {
+        self.font_start + 5*(digit & 0xf) as u16
+    }
+
+    /// address of the 8x10 SUPER-CHIP sprite of hex digit `digit`
+    pub fn big_font_address(&self, digit: u8) -> u16 {
+        self.font_start + 80 + 10*(digit & 0xf) as u16
     }
 
     pub fn start(&self) -> u16 {
         Self::CARTRIDGE_START
     }
 
-    pub fn len(&self) -> u16 {
+    /// end of the loaded program (not its length, which is `len() - start()`)
+    pub fn len(&self) -> usize {
       self.fin
     }
 
-    pub fn set_memory(&mut self, address: u16, val: u8) {
-        self.memory[address as usize] = val
+    pub fn is_empty(&self) -> bool {
+        self.fin == Self::CARTRIDGE_START as usize
+    }
+
+    pub fn set_memory(&mut self, address: u16, val: u8) -> Result<(), MemoryError> {
+        match self.memory.get_mut(address as usize) {
+            Some(m) => {
+                let old = std::mem::replace(m, val);
+                self.watch(address, Access::Write, old, val);
+                Ok(())
+            },
+            None => Err(MemoryError { address })
+        }
+    }
+
+    pub fn get_memory(&self, address: u16) -> Result<u8, MemoryError> {
+        let val = self.memory.get(address as usize).copied().ok_or(MemoryError { address })?;
+        self.watch(address, Access::Read, val, val);
+        Ok(val)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint)
+    }
+
+    pub fn remove_watchpoint(&mut self, n: usize) -> Option<Watchpoint> {
+        if n < self.watchpoints.len() { Some(self.watchpoints.remove(n)) } else { None }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// notes that the instruction at `address` is about to be executed
+    pub fn watch_execute(&self, address: u16) {
+        let val = self.memory.get(address as usize).copied().unwrap_or(0);
+        self.watch(address, Access::Execute, val, val)
+    }
+
+    /// the watched accesses since the last call
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        self.hits.get_mut().drain(..).collect()
+    }
+
+    fn watch(&self, address: u16, access: Access, old: u8, new: u8) {
+        for (watchpoint, _) in self.watchpoints.iter().enumerate().filter(|(_, w)| w.watches(address, access)) {
+            self.hits.borrow_mut().push(WatchHit { watchpoint, pc: 0, address, access, old, new })
+        }
+    }
+
+    pub(crate) fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.memory);
+        w.u32(self.fin as u32);
+        w.u16(self.font_start)
     }
 
-    pub fn get_memory(&mut self, address: u16) -> u8 {
-        self.memory[address as usize]
+    pub(crate) fn restore(r: &mut StateReader) -> Result<Self, SnapshotError> {
+        let memory = r.bytes()?.to_vec();
+        if memory.len() != Self::MEMORY_SIZE && memory.len() != Self::XO_CHIP_MEMORY_SIZE {
+            return Err(SnapshotError::Invalid("memory size"))
+        }
+        let fin = r.u32()? as usize;
+        let font_start = r.u16()?;
+        if fin > memory.len() || font_start as usize + Font::LEN > memory.len() {
+            return Err(SnapshotError::Invalid("program or font outside of memory"))
+        }
+        Ok(Self { memory, fin, font_start, watchpoints: Vec::new(), hits: RefCell::new(Vec::new()) })
     }
 
+    /// the two bytes at `address`, which may lie anywhere in memory (programs can run self-written code)
     pub fn get_opcode_from(&self, address: u16) -> Result<u16, Error> {
-        if address > self.len() {
-            Err(Error::new(ErrorKind::UnexpectedEof, "end of file"))
+        let au = address as usize;
+        if au + 1 >= self.memory.len() {
+            Err(Error::new(ErrorKind::UnexpectedEof, "end of memory"))
         } else {
-            let au = address as usize;
             Ok( u16::from_be_bytes([self.memory[au], self.memory[au+1]]) )
         }
     }
@@ -0,0 +1,266 @@
+use chip8_emu::{random, Font, TraceFormat, Keymap, KeymapConfig, MemoryOverflow, Quirks, TermDisplay, Watchpoint};
+
+pub const USAGE: &str = "\
+usage: chip8-emu [COMMAND] ROM [OPTIONS]
+
+commands:
+  run        play ROM in a window (default)
+  disasm     disassemble ROM into assembly source with labels, code and data
+  asm        assemble the source file ROM, or compile it if it is Octo (.8o), into a ROM image
+             (-o, default: ROM.ch8) and a .sym symbol map
+  trace      run ROM without a window, printing every executed instruction
+  headless   run ROM without a window and print the final display
+  gdb        wait for a GDB remote debugger on 127.0.0.1 and let it run ROM
+  trace-diff find the first record at which the trace ROM and a second trace file differ
+
+options:
+  -o, --output PATH         write listings, traces and displays to PATH instead of stdout
+  -q, --quirks PROFILE      vip, chip48, schip or xo-chip (default: vip)
+      --memory-overflow M   wrap or fault when I points past the end of memory (default: wrap)
+  -c, --cycles-per-frame N  instructions executed per 60 Hz frame (default: 1000 for trace and headless, else 29333)
+  -n, --cycles N            stop after N instructions (default: 5000 for trace and headless)
+  -f, --frames N            stop trace and headless runs after N frames
+      --format FORMAT       text, jsonl or csv: how trace writes the instructions (default: text)
+  -s, --scale WxH           size of a low resolution pixel in the window (default: 16x14)
+      --colors C0,C1,C2,C3  background, plane 1, plane 2 and both planes as RRGGBB hex
+      --font NAME|PATH      default, vip, dream6800, eti660, schip or a font file (80 or 240 bytes)
+      --font-address ADDR   where the font is loaded (default: 0x000)
+      --volume V            beeper volume from 0.0 to 1.0 (default: 0.25)
+      --tone HZ             beeper frequency (default: 440)
+      --wav PATH            render the sound of trace and headless runs to PATH
+  -k, --keys LAYOUT|PATH    qwerty, azerty, qwertz, dvorak or a keymap file (default: qwerty)
+      --load-state PATH     start from a save state instead of the beginning of ROM
+      --save-state PATH     save the state at the end of trace and headless runs to PATH
+      --seed N              seed of the random number generator, runs with the same seed repeat exactly
+      --random GENERATOR    xorshift or vip, the routine of the COSMAC VIP interpreter (default: xorshift)
+      --record PATH         record the keypad input into the movie file PATH
+      --replay PATH         play the keypad input of a movie and report where the run deviates from it
+      --input PATH          keypad input for trace and headless runs, lines of FRAME KEYS...
+      --screenshot PATH     write the final display of trace and headless runs as .png or .pbm
+      --hash                print a hash of the final display instead of the display itself
+      --debug               control the window from a debugger prompt in the terminal (type help)
+      --watch [rwx:]ADDR[-END]
+                            log reads, writes (default) or execution of memory, pauses with --debug
+      --rewind SECONDS      how far the window can rewind, 0 turns it off (default: 30)
+      --port N              TCP port of the gdb command (default: 1234)
+  -h, --help                show this help
+";
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Command {
+  Run,
+  Disasm,
+  Asm,
+  Trace,
+  Headless,
+  Gdb,
+  TraceDiff
+}
+
+pub struct Options {
+  pub command: Command,
+  pub rom: String,
+  pub output: Option<String>,
+  pub quirks: Quirks,
+  pub font: Font,
+  pub font_address: u16,
+  pub cycles_per_frame: u32,
+  pub cycle_limit: Option<u64>,
+  pub scale: (usize, usize),
+  pub palette: [u32; 4],
+  pub volume: f32,
+  pub tone: f32,
+  pub wav: Option<String>,
+  pub keymap: Keymap,
+  pub load_state: Option<String>,
+  pub save_state: Option<String>,
+  pub rewind_seconds: u32,
+  pub seed: Option<u64>,
+  pub random: String,
+  pub record: Option<String>,
+  pub replay: Option<String>,
+  pub frames: Option<u32>,
+  pub input: Option<String>,
+  pub screenshot: Option<String>,
+  pub hash: bool,
+  pub debug: bool,
+  pub watchpoints: Vec<Watchpoint>,
+  pub port: u16,
+  pub trace_format: TraceFormat,
+  pub against: String // the second trace of trace-diff
+}
+
+impl Options {
+  fn new(command: Command) -> Self {
+    let cycles_per_frame = match command {
+      // ticks within the default 5000 cycles of a run without a window
+      Command::Trace | Command::Headless => 1000,
+      _ => 29_333 // ~1.76 MHz, cosmac vp
+    };
+    Self { command, rom: String::new(), output: None, quirks: Quirks::default(),
+      font: Font::default(), font_address: 0,
+      cycles_per_frame,
+      cycle_limit: None, scale: (16, 14), palette: TermDisplay::DEFAULT_PALETTE, volume: 0.25, tone: 440.0, wav: None,
+      keymap: Keymap::default(), load_state: None, save_state: None, rewind_seconds: 30,
+      seed: None, random: "xorshift".to_string(), record: None, replay: None,
+      frames: None, input: None, screenshot: None, hash: false, debug: false, watchpoints: Vec::new(), port: 1234,
+      trace_format: TraceFormat::Text, against: String::new() }
+  }
+}
+
+/// what the command line asks for: options to run with, or just the help text
+pub enum Parsed {
+  Options(Box<Options>),
+  Help
+}
+
+fn value<I: Iterator<Item=String>>(args: &mut I, flag: &str) -> Result<String, String> {
+  args.next().ok_or(format!("{} needs a value", flag))
+}
+
+fn number<T: std::str::FromStr>(text: &str, flag: &str) -> Result<T, String> {
+  text.parse().map_err(|_| format!("{} expects a number, got {}", flag, text))
+}
+
+/// decimal, or hexadecimal with a 0x prefix
+fn address(text: &str, flag: &str) -> Result<u16, String> {
+  match text.strip_prefix("0x") {
+    Some(hex) => u16::from_str_radix(hex, 16).map_err(|_| format!("{} expects an address, got {}", flag, text)),
+    None => number(text, flag)
+  }
+}
+
+fn parse_scale(text: &str) -> Result<(usize, usize), String> {
+  let (w, h) = match text.split_once('x') {
+    Some((w, h)) => (w, h),
+    None => (text, text)
+  };
+  Ok((number(w, "--scale")?, number(h, "--scale")?))
+}
+
+fn parse_palette(text: &str) -> Result<[u32; 4], String> {
+  let mut palette = TermDisplay::DEFAULT_PALETTE;
+  for (n, color) in text.split(',').enumerate() {
+    if n >= palette.len() { return Err("--colors takes at most 4 colors".to_string()) }
+    palette[n] = u32::from_str_radix(color.trim_start_matches('#'), 16).map_err(|_| format!("{} is not a RRGGBB color", color))?
+  }
+  Ok(palette)
+}
+
+pub fn parse<I: Iterator<Item=String>>(args: I) -> Result<Parsed, String> {
+  let mut rest = args.peekable();
+  let command = match rest.peek().map(|a| a.as_str()) {
+    Some("run") => Some(Command::Run),
+    Some("disasm") => Some(Command::Disasm),
+    Some("asm") => Some(Command::Asm),
+    Some("trace") => Some(Command::Trace),
+    Some("headless") => Some(Command::Headless),
+    Some("gdb") => Some(Command::Gdb),
+    Some("trace-diff") => Some(Command::TraceDiff),
+    _ => None
+  };
+  if command.is_some() { rest.next(); }
+  let mut opts = Options::new(command.unwrap_or(Command::Run));
+  let mut overflow = None; // applied after parsing, --quirks may come later
+  let mut keys = None;     // keymap files have per-ROM sections, so this waits for the ROM too
+
+  while let Some(arg) = rest.next() {
+    match arg.as_str() {
+      "-h" | "--help" => return Ok(Parsed::Help),
+      "-o" | "--output" => opts.output = Some(value(&mut rest, &arg)?),
+      "-q" | "--quirks" => {
+        let name = value(&mut rest, &arg)?;
+        opts.quirks = Quirks::from_name(&name).ok_or(format!("unknown quirk profile {}, choose one of {}", name, Quirks::PRESETS.join(", ")))?
+      },
+      "--memory-overflow" => overflow = Some(match value(&mut rest, &arg)?.as_str() {
+        "wrap" => MemoryOverflow::Wrap,
+        "fault" => MemoryOverflow::Fault,
+        other => return Err(format!("--memory-overflow is wrap or fault, not {}", other))
+      }),
+      "-c" | "--cycles-per-frame" => opts.cycles_per_frame = number(&value(&mut rest, &arg)?, &arg)?,
+      "-n" | "--cycles" => opts.cycle_limit = Some(number(&value(&mut rest, &arg)?, &arg)?),
+      "--format" => {
+        let name = value(&mut rest, &arg)?;
+        opts.trace_format = TraceFormat::from_name(&name).ok_or(format!("--format is one of {}, not {}", TraceFormat::NAMES.join(", "), name))?
+      },
+      "-f" | "--frames" => opts.frames = Some(number(&value(&mut rest, &arg)?, &arg)?),
+      "-s" | "--scale" => opts.scale = parse_scale(&value(&mut rest, &arg)?)?,
+      "--colors" => opts.palette = parse_palette(&value(&mut rest, &arg)?)?,
+      "--font" => {
+        let name = value(&mut rest, &arg)?;
+        opts.font = match Font::from_name(&name) {
+          Some(font) => font,
+          None => Font::from_file(&name).map_err(|e| format!("font {} is neither one of {} nor a readable font file: {}", name, Font::NAMES.join(", "), e))?
+        }
+      },
+      "--font-address" => opts.font_address = address(&value(&mut rest, &arg)?, &arg)?,
+      "--volume" => opts.volume = number(&value(&mut rest, &arg)?, &arg)?,
+      "--tone" => opts.tone = number(&value(&mut rest, &arg)?, &arg)?,
+      "--wav" => opts.wav = Some(value(&mut rest, &arg)?),
+      "-k" | "--keys" => keys = Some(value(&mut rest, &arg)?),
+      "--load-state" => opts.load_state = Some(value(&mut rest, &arg)?),
+      "--save-state" => opts.save_state = Some(value(&mut rest, &arg)?),
+      "--seed" => opts.seed = Some(number(&value(&mut rest, &arg)?, &arg)?),
+      "--random" => {
+        let name = value(&mut rest, &arg)?;
+        if !random::NAMES.contains(&name.as_str()) {
+          return Err(format!("unknown random number generator {}, choose one of {}", name, random::NAMES.join(", ")))
+        }
+        opts.random = name
+      },
+      "--record" => opts.record = Some(value(&mut rest, &arg)?),
+      "--replay" => opts.replay = Some(value(&mut rest, &arg)?),
+      "--input" => opts.input = Some(value(&mut rest, &arg)?),
+      "--screenshot" => {
+        let path = value(&mut rest, &arg)?;
+        if !path.ends_with(".png") && !path.ends_with(".pbm") {
+          return Err(format!("--screenshot writes .png or .pbm files, not {}", path))
+        }
+        opts.screenshot = Some(path)
+      },
+      "--hash" => opts.hash = true,
+      "--debug" => opts.debug = true,
+      "--watch" => opts.watchpoints.push(Watchpoint::parse(&value(&mut rest, &arg)?).map_err(|e| format!("--watch: {}", e))?),
+      "--port" => opts.port = number(&value(&mut rest, &arg)?, &arg)?,
+      "--rewind" => opts.rewind_seconds = number(&value(&mut rest, &arg)?, &arg)?,
+      flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+      rom if opts.rom.is_empty() => opts.rom = rom.to_string(),
+      second if opts.command == Command::TraceDiff && opts.against.is_empty() => opts.against = second.to_string(),
+      extra => return Err(format!("unexpected argument {}", extra))
+    }
+  }
+
+  if let Some(overflow) = overflow {
+    opts.quirks.memory_overflow = overflow
+  }
+  if opts.rom.is_empty() {
+    return Err("insert cartridge (.rom file)".to_string())
+  }
+  if opts.command == Command::TraceDiff && opts.against.is_empty() {
+    return Err("trace-diff compares two traces".to_string())
+  }
+  if [opts.record.is_some(), opts.replay.is_some(), opts.input.is_some()].iter().filter(|on| **on).count() > 1 {
+    return Err("only one of --record, --replay and --input can be used".to_string())
+  }
+  if opts.debug && (opts.record.is_some() || opts.replay.is_some()) {
+    return Err("--debug stops and steps the machine mid-frame, which movies cannot follow".to_string())
+  }
+  if opts.command == Command::Gdb && (opts.record.is_some() || opts.replay.is_some() || opts.input.is_some()) {
+    return Err("gdb decides when the machine runs, there is no keypad input to --record, --replay or --input".to_string())
+  }
+  if opts.load_state.is_some() && (opts.record.is_some() || opts.replay.is_some()) {
+    return Err("movies start at the beginning of the ROM, not from --load-state".to_string())
+  }
+  for watchpoint in &mut opts.watchpoints {
+    watchpoint.pause = opts.debug
+  }
+  if let Some(keys) = keys {
+    opts.keymap = match Keymap::preset(&keys) {
+      Some(keymap) => keymap,
+      None => KeymapConfig::from_file(&keys)
+        .map_err(|e| format!("keys {} are neither one of {} nor a usable keymap file: {}", keys, Keymap::PRESETS.join(", "), e))?
+        .keymap(&opts.rom)
+    }
+  }
+  Ok(Parsed::Options(Box::new(opts)))
+}
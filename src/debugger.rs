@@ -0,0 +1,262 @@
+use std::fmt;
+
+use crate::instruction::from_opcode;
+use crate::cartridge::Watchpoint;
+use crate::state::Chip8State;
+
+/// what a breakpoint condition compares
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Operand {
+    V(u8),
+    I,
+    DelayTimer,
+    SoundTimer
+}
+
+impl Operand {
+    fn parse(text: &str) -> Option<Self> {
+        let text = text.to_uppercase();
+        match text.as_str() {
+            "I" => Some(Operand::I),
+            "DT" => Some(Operand::DelayTimer),
+            "ST" => Some(Operand::SoundTimer),
+            _ => text.strip_prefix('V').and_then(|n| u8::from_str_radix(n, 16).ok()).filter(|n| *n < 16).map(Operand::V)
+        }
+    }
+
+    /// the largest value the operand can have
+    fn max(&self) -> u16 {
+        match self {
+            Operand::I => 0xffff,
+            _ => 0xff
+        }
+    }
+
+    fn value(&self, cas: &Chip8State) -> u16 {
+        match self {
+            Operand::V(n) => cas.v(*n) as u16,
+            Operand::I => cas.i(),
+            Operand::DelayTimer => cas.delay_timer() as u16,
+            Operand::SoundTimer => cas.sound_timer() as u16
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::V(n) => write!(f, "V{:X}", n),
+            Operand::I => write!(f, "I"),
+            Operand::DelayTimer => write!(f, "DT"),
+            Operand::SoundTimer => write!(f, "ST")
+        }
+    }
+}
+
+/// `operand op value`, e.g. `V3 == 0x10`
+#[derive(Debug,Clone,PartialEq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub op: &'static str,
+    pub value: u16
+}
+
+impl Condition {
+    const OPS: [&'static str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+    fn holds(&self, cas: &Chip8State) -> bool {
+        let x = self.operand.value(cas);
+        match self.op {
+            "==" => x == self.value,
+            "!=" => x != self.value,
+            "<=" => x <= self.value,
+            ">=" => x >= self.value,
+            "<" => x < self.value,
+            _ => x > self.value
+        }
+    }
+}
+
+/// stops the emulation when the program counter reaches `pc` and the condition (if any) holds
+#[derive(Debug,Clone,PartialEq)]
+pub struct Breakpoint {
+    pub pc: u16,
+    pub condition: Option<Condition>
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#05x}", self.pc)?;
+        match &self.condition {
+            Some(c) => write!(f, " if {} {} {:#x}", c.operand, c.op, c.value),
+            None => Ok(())
+        }
+    }
+}
+
+/// a line typed into the debugger
+#[derive(Debug,Clone,PartialEq)]
+pub enum DebugCommand {
+    Continue,
+    Pause,
+    Step(u32),  // instructions
+    Frame(u32), // frames, then pause again
+    Break(Breakpoint),
+    Delete(usize),
+    Watch(Watchpoint),
+    Unwatch(usize),
+    List,
+    Registers,
+    Help,
+    Quit
+}
+
+pub const HELP: &str = "\
+c, continue           resume the emulation
+p, pause              pause the emulation
+s, step [N]           execute N instructions (default 1) while paused
+f, frame [N]          run N frames (default 1), then pause
+b, break ADDR [if OPERAND OP VALUE]
+                      stop at ADDR, optionally only if e.g. V3 == 0x10, I >= 0x300 or DT != 0
+d, delete N           remove breakpoint N
+w, watch [rwx:]ADDR[-END] [log]
+                      stop after an instruction reads, writes (default) or executes ADDR to END,
+                      or only log the access
+u, unwatch N          remove watchpoint N
+l, list               list the breakpoints and watchpoints
+r, regs               show V0-VF, I, PC, timers, stack and the next instruction
+q, quit               close the emulator
+";
+
+fn number(text: &str) -> Result<u32, String> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse()
+    }.map_err(|_| format!("{} is not a number", text))
+}
+
+fn count(arg: Option<&str>) -> Result<u32, String> {
+    arg.map(number).unwrap_or(Ok(1))
+}
+
+pub fn parse_command(line: &str) -> Result<DebugCommand, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let arg = words.get(1).copied();
+    match words.first().copied().unwrap_or("") {
+        "c" | "continue" => Ok(DebugCommand::Continue),
+        "p" | "pause" => Ok(DebugCommand::Pause),
+        "s" | "step" => Ok(DebugCommand::Step(count(arg)?)),
+        "f" | "frame" => Ok(DebugCommand::Frame(count(arg)?)),
+        "b" | "break" => {
+            let pc = number(arg.ok_or("break needs an address")?)?;
+            if pc > 0xffff { return Err(format!("{:#x} is outside of memory", pc)) }
+            let condition = match &words[2..] {
+                [] => None,
+                ["if", operand, op, value] => {
+                    let operand = Operand::parse(operand).ok_or(format!("{} is not V0-VF, I, DT or ST", operand))?;
+                    let value = number(value)?;
+                    if value > operand.max() as u32 {
+                        return Err(format!("{} only goes up to {:#x}", operand, operand.max()))
+                    }
+                    Some(Condition {
+                        operand,
+                        op: Condition::OPS.iter().find(|o| *o == op).ok_or(format!("{} is not one of {}", op, Condition::OPS.join(" ")))?,
+                        value: value as u16
+                    })
+                },
+                _ => return Err("conditions look like: if V3 == 0x10".to_string())
+            };
+            Ok(DebugCommand::Break(Breakpoint { pc: pc as u16, condition }))
+        },
+        "d" | "delete" => Ok(DebugCommand::Delete(number(arg.ok_or("delete needs a breakpoint number")?)? as usize)),
+        "w" | "watch" => {
+            let mut watchpoint = Watchpoint::parse(arg.ok_or("watch needs an address")?)?;
+            match &words[2..] {
+                [] => {},
+                ["log"] => watchpoint.pause = false,
+                _ => return Err("watchpoints look like: watch rw:0x300-0x30f log".to_string())
+            }
+            Ok(DebugCommand::Watch(watchpoint))
+        },
+        "u" | "unwatch" => Ok(DebugCommand::Unwatch(number(arg.ok_or("unwatch needs a watchpoint number")?)? as usize)),
+        "l" | "list" => Ok(DebugCommand::List),
+        "r" | "regs" => Ok(DebugCommand::Registers),
+        "h" | "help" | "?" => Ok(DebugCommand::Help),
+        "q" | "quit" => Ok(DebugCommand::Quit),
+        "" => Err(String::new()),
+        other => Err(format!("unknown command {}, type help for a list", other))
+    }
+}
+
+/// breakpoints, and where the emulation was resumed, so that it does not stop right there again
+#[derive(Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    resumed_at: Option<u16>
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// number of a breakpoint that stops the machine before its next instruction
+    pub fn hit(&mut self, cas: &Chip8State) -> Option<usize> {
+        if self.resumed_at.take() == Some(cas.pc) { return None }
+        self.breakpoints.iter().position(|b| b.pc == cas.pc && b.condition.as_ref().map(|c| c.holds(cas)).unwrap_or(true))
+    }
+
+    /// the next instruction may run even though it has a breakpoint
+    pub fn resume(&mut self, cas: &Chip8State) {
+        self.resumed_at = Some(cas.pc)
+    }
+}
+
+/// registers, timers, stack and the next instruction, for the debugger
+pub fn describe(cas: &Chip8State) -> String {
+    let vs: Vec<String> = (0..16).map(|n| format!("V{:X}={:02x}", n, cas.v(n))).collect();
+    let stack: Vec<String> = cas.stack().iter().map(|a| format!("{:#05x}", a)).collect();
+    let next = match cas.cartridge.get_opcode_from(cas.pc) {
+        Ok(opcode) => match from_opcode(opcode) {
+            Ok(instr) => format!("{:#06x}  {}", opcode, instr),
+            Err(e) => format!("{:#06x}  ({})", opcode, e)
+        },
+        Err(e) => e.to_string()
+    };
+    format!("{}\n{}\nI={:#06x} PC={:#06x} DT={:02x} ST={:02x} stack=[{}]\nnext: {}",
+        vs[..8].join(" "), vs[8..].join(" "), cas.i(), cas.pc, cas.delay_timer(), cas.sound_timer(), stack.join(" "), next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_conditional_breakpoints() {
+        let b = Breakpoint { pc: 0x2a4, condition: Some(Condition { operand: Operand::V(3), op: "==", value: 0x10 }) };
+        assert_eq!(parse_command("b 0x2a4 if v3 == 0x10"), Ok(DebugCommand::Break(b.clone())));
+        assert_eq!(b.to_string(), "0x2a4 if V3 == 0x10");
+        assert!(parse_command("b 0x2a4 if VG == 1").is_err());
+        assert!(parse_command("b 0x2a4 if I =< 1").is_err());
+        assert!(parse_command("b 0x2a4 if I == 0x10000").is_err());
+        assert!(parse_command("b 0x2a4 if V3 == 0x100").is_err());
+        assert!(parse_command("b 0x2a4 if I == 0xffff").is_ok());
+    }
+
+    #[test]
+    fn parses_watchpoints() {
+        let w = Watchpoint { start: 0x300, end: 0x30f, read: true, write: true, execute: false, pause: false };
+        assert_eq!(parse_command("watch rw:0x300-0x30f log"), Ok(DebugCommand::Watch(w.clone())));
+        assert_eq!(w.to_string(), "rw:0x300-0x30f (log)");
+        assert_eq!(parse_command("w 0x2a4"), Ok(DebugCommand::Watch(Watchpoint::parse("w:0x2a4").unwrap())));
+        assert!(parse_command("w q:0x2a4").is_err());
+        assert!(parse_command("w 0x30f-0x300").is_err());
+    }
+
+    #[test]
+    fn counts_default_to_one() {
+        assert_eq!(parse_command("s"), Ok(DebugCommand::Step(1)));
+        assert_eq!(parse_command("frame 30"), Ok(DebugCommand::Frame(30)));
+        assert!(parse_command("jump").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::instruction::{encoding_of, mnemonic, Arg, Encoding};
+
+/// what a byte of the program turned out to be
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Kind {
+    Data,
+    Instruction, // the first byte of one
+    Operand      // a later byte of one
+}
+
+/// a ROM split into the code reachable from its entry point and the data in between, with
+/// labels for jump and call targets and for the addresses loaded into I
+///
+/// Its `Display` is assembly source in the syntax of `instruction::ENCODINGS` that assembles
+/// into the same bytes again: code as instructions, data as `db` lines, and data that I
+/// points to as one byte per line with a picture of the sprite row.
+pub struct Disassembly {
+    origin: u16,
+    rom: Vec<u8>,
+    kinds: Vec<Kind>,
+    labels: BTreeMap<u16, String>,
+    sprites: BTreeSet<u16>
+}
+
+/// follows every path through `rom`, which is loaded at `origin`, from its first instruction on
+pub fn disassemble(rom: &[u8], origin: u16) -> Disassembly {
+    let mut d = Disassembly { origin, rom: rom.to_vec(), kinds: vec![Kind::Data; rom.len()], labels: BTreeMap::new(), sprites: BTreeSet::new() };
+    let mut targets = BTreeMap::new();
+    targets.insert(origin, "start".to_string());
+    let mut todo = vec![origin];
+    while let Some(mut address) = todo.pop() {
+        while let Some(e) = d.fresh_instruction(address) {
+            let opcode = d.word(address).unwrap_or(0);
+            let target = Arg::Addr.get(opcode);
+            for n in 0..e.size() {
+                d.kinds[(address - origin + n) as usize] = if n == 0 { Kind::Instruction } else { Kind::Operand }
+            }
+            let next = address.wrapping_add(e.size()); // past the end of memory there is nothing to decode
+            match (e.mnemonic, e.args) {
+                ("JP", [Arg::Addr]) => {
+                    targets.entry(target).or_insert(format!("l_{:03x}", target));
+                    todo.push(target);
+                    break
+                },
+                ("JP", _) => { // a jump table, usually of JP instructions, indexed by V0
+                    targets.entry(target).or_insert(format!("table_{:03x}", target));
+                    todo.push(target);
+                    break
+                },
+                ("CALL", _) => {
+                    targets.insert(target, format!("sub_{:03x}", target));
+                    todo.push(target)
+                },
+                ("RET", _) | ("EXIT", _) => break,
+                ("SE", _) | ("SNE", _) | ("SKP", _) | ("SKNP", _) => {
+                    let long = d.word(next) == Some(0xf000);
+                    todo.push(next.wrapping_add(if long { 4 } else { 2 }))
+                },
+                ("LD", [Arg::Word("I"), Arg::Addr]) => {
+                    targets.entry(target).or_insert(format!("data_{:03x}", target));
+                    d.sprites.insert(target);
+                },
+                ("LD", [Arg::Word("I"), Arg::Long]) => {
+                    let target = d.word(address + 2).unwrap_or(0);
+                    targets.entry(target).or_insert(format!("data_{:04x}", target));
+                    d.sprites.insert(target);
+                },
+                _ => {}
+            }
+            address = next
+        }
+    }
+    // only addresses at which a line starts can have a label
+    d.labels = targets.into_iter().filter(|(a, _)| d.kind(*a).map(|k| k != Kind::Operand).unwrap_or(false)).collect();
+    d
+}
+
+impl Disassembly {
+    fn kind(&self, address: u16) -> Option<Kind> {
+        self.kinds.get(address.checked_sub(self.origin)? as usize).copied()
+    }
+
+    fn word(&self, address: u16) -> Option<u16> {
+        let n = address.checked_sub(self.origin)? as usize;
+        Some(u16::from_be_bytes([*self.rom.get(n)?, *self.rom.get(n + 1)?]))
+    }
+
+    /// the instruction at `address`, unless it is not one or was decoded already
+    fn fresh_instruction(&self, address: u16) -> Option<&'static Encoding> {
+        let e = encoding_of(self.word(address)?).filter(|e| e.mnemonic != "SYS")?; // 0NNN is mostly padding
+        let all_data = (0..e.size()).all(|n| address.checked_add(n).and_then(|a| self.kind(a)) == Some(Kind::Data));
+        if all_data { Some(e) } else { None }
+    }
+
+    /// whether `address` holds the first byte of an instruction
+    pub fn is_code(&self, address: u16) -> bool {
+        self.kind(address) == Some(Kind::Instruction)
+    }
+
+    pub fn labels(&self) -> &BTreeMap<u16, String> {
+        &self.labels
+    }
+
+    fn name(&self, address: u16) -> String {
+        self.labels.get(&address).cloned().unwrap_or_else(|| format!("{:#05x}", address))
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = self.origin as usize + self.rom.len(); // 0x10000 for the largest XO-CHIP programs
+        let mut next = self.origin as usize;
+        let mut sprite = false;
+        while next < end {
+            let address = next as u16;
+            if let Some(label) = self.labels.get(&address) {
+                writeln!(f, "{}:", label)?;
+                sprite = self.sprites.contains(&address);
+            }
+            if self.is_code(address) {
+                let opcode = self.word(address).unwrap_or(0);
+                let long = self.word(address.wrapping_add(2)).unwrap_or(0);
+                let text = mnemonic(opcode, long, |a| self.name(a)).unwrap_or_default();
+                writeln!(f, "    {:<24}; {:#05x}  {:04x}", text, address, opcode)?;
+                next += encoding_of(opcode).map(|e| e.size()).unwrap_or(2) as usize;
+                sprite = false;
+                continue
+            }
+            // data up to the next instruction or label
+            let run_end = (next + 1..end).find(|a| self.is_code(*a as u16) || self.labels.contains_key(&(*a as u16))).unwrap_or(end);
+            let bytes = &self.rom[next - self.origin as usize..run_end - self.origin as usize];
+            if sprite {
+                for byte in bytes {
+                    let picture: String = (0..8).map(|n| if byte & 0x80 >> n != 0 { '#' } else { '.' }).collect();
+                    writeln!(f, "    db {:#010b}           ; {}", byte, picture)?;
+                }
+            } else {
+                for line in bytes.chunks(8) {
+                    let hex: Vec<String> = line.iter().map(|b| format!("{:#04x}", b)).collect();
+                    writeln!(f, "    db {}", hex.join(", "))?;
+                }
+            }
+            next = run_end
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_jumps_and_leaves_sprites_alone() {
+        // jump over a sprite, point I at it, call a subroutine behind it that skips over a long load
+        let rom = [0x12, 0x04, 0x3c, 0x42, 0xa2, 0x02, 0x22, 0x0a, 0x12, 0x08,
+                   0x30, 0x00, 0xf0, 0x00, 0x02, 0x02, 0x00, 0xee];
+        let d = disassemble(&rom, 0x200);
+        assert!(d.is_code(0x204) && d.is_code(0x20a) && d.is_code(0x20c) && d.is_code(0x210));
+        assert!(!d.is_code(0x202) && !d.is_code(0x20e));
+        let text = d.to_string();
+        assert!(text.contains("    JP l_204"), "{}", text);
+        assert!(text.contains("data_202:\n    db 0b00111100           ; ..####..\n    db 0b01000010"), "{}", text);
+        assert!(text.contains("    CALL sub_20a"), "{}", text);
+        assert!(text.contains("    LD I, LONG data_202"), "{}", text);
+    }
+
+    #[test]
+    fn reassembles_into_the_same_bytes() {
+        // a sprite byte, then code at odd addresses with a jump table and a long load
+        let rom = [0x12, 0x03, 0x3c, 0xa2, 0x02, 0x60, 0x02, 0xb2, 0x0b, 0x00, 0x00,
+                   0x12, 0x0f, 0x12, 0x13, 0xf0, 0x00, 0x02, 0x02, 0x12, 0x13];
+        let d = disassemble(&rom, 0x200);
+        assert!(d.is_code(0x203) && d.is_code(0x20b) && d.is_code(0x20f) && !d.is_code(0x209));
+        let text = d.to_string();
+        assert!(text.contains("    JP V0, table_20b"), "{}", text);
+        let assembly = crate::asm::assemble(&text, "disassembly").unwrap_or_else(|e| panic!("{}\n{}", e, text));
+        assert_eq!(assembly.rom, rom, "{}", text);
+    }
+
+    #[test]
+    fn jumps_into_the_middle_of_an_instruction_stay_numbers() {
+        let rom = [0x60, 0x12, 0x12, 0x01];
+        let text = disassemble(&rom, 0x200).to_string();
+        assert!(text.contains("    JP 0x201"), "{}", text);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{prelude::*, Error, ErrorKind};
+use std::fs::File;
+
+/// hex digit sprites: 4x5 for FX29 and SUPER-CHIP 8x10 for FX30
+#[derive(Debug,Clone,PartialEq)]
+pub struct Font {
+    pub small: [u8; 80],
+    pub big: [u8; 160]
+}
+
+const DEFAULT_SMALL: &[u8; 80] = include_bytes!("../fonts");
+const DEFAULT_BIG: &[u8; 160] = include_bytes!("../fonts-big");
+
+const VIP: [u8; 80] = [
+    0xf0, 0x90, 0x90, 0x90, 0xf0,  0x60, 0x20, 0x20, 0x20, 0x70,  0xf0, 0x10, 0xf0, 0x80, 0xf0,  0xf0, 0x10, 0xf0, 0x10, 0xf0,
+    0xa0, 0xa0, 0xf0, 0x20, 0x20,  0xf0, 0x80, 0xf0, 0x10, 0xf0,  0xf0, 0x80, 0xf0, 0x90, 0xf0,  0xf0, 0x10, 0x10, 0x10, 0x10,
+    0xf0, 0x90, 0xf0, 0x90, 0xf0,  0xf0, 0x90, 0xf0, 0x10, 0xf0,  0xf0, 0x90, 0xf0, 0x90, 0x90,  0xf0, 0x50, 0x70, 0x50, 0xf0,
+    0xf0, 0x80, 0x80, 0x80, 0xf0,  0xf0, 0x50, 0x50, 0x50, 0xf0,  0xf0, 0x80, 0xf0, 0x80, 0xf0,  0xf0, 0x80, 0xf0, 0x80, 0x80];
+
+const DREAM_6800: [u8; 80] = [
+    0xe0, 0xa0, 0xa0, 0xa0, 0xe0,  0x40, 0x40, 0x40, 0x40, 0x40,  0xe0, 0x20, 0xe0, 0x80, 0xe0,  0xe0, 0x20, 0xe0, 0x20, 0xe0,
+    0x80, 0xa0, 0xa0, 0xe0, 0x20,  0xe0, 0x80, 0xe0, 0x20, 0xe0,  0xe0, 0x80, 0xe0, 0xa0, 0xe0,  0xe0, 0x20, 0x20, 0x20, 0x20,
+    0xe0, 0xa0, 0xe0, 0xa0, 0xe0,  0xe0, 0xa0, 0xe0, 0x20, 0xe0,  0xe0, 0xa0, 0xe0, 0xa0, 0xa0,  0xc0, 0xa0, 0xe0, 0xa0, 0xc0,
+    0xe0, 0x80, 0x80, 0x80, 0xe0,  0xc0, 0xa0, 0xa0, 0xa0, 0xc0,  0xe0, 0x80, 0xe0, 0x80, 0xe0,  0xe0, 0x80, 0xc0, 0x80, 0x80];
+
+const ETI_660: [u8; 80] = [
+    0xe0, 0xa0, 0xa0, 0xa0, 0xe0,  0x20, 0x20, 0x20, 0x20, 0x20,  0xe0, 0x20, 0xe0, 0x80, 0xe0,  0xe0, 0x20, 0xe0, 0x20, 0xe0,
+    0xa0, 0xa0, 0xe0, 0x20, 0x20,  0xe0, 0x80, 0xe0, 0x20, 0xe0,  0xe0, 0x80, 0xe0, 0xa0, 0xe0,  0xe0, 0x20, 0x20, 0x20, 0x20,
+    0xe0, 0xa0, 0xe0, 0xa0, 0xe0,  0xe0, 0xa0, 0xe0, 0x20, 0xe0,  0xe0, 0xa0, 0xe0, 0xa0, 0xa0,  0x80, 0x80, 0xe0, 0xa0, 0xe0,
+    0xe0, 0x80, 0x80, 0x80, 0xe0,  0x20, 0x20, 0xe0, 0xa0, 0xe0,  0xe0, 0x80, 0xe0, 0x80, 0xe0,  0xe0, 0x80, 0xc0, 0x80, 0x80];
+
+const SCHIP: [u8; 80] = [
+    0xf0, 0x90, 0x90, 0x90, 0xf0,  0x20, 0x60, 0x20, 0x20, 0x70,  0xf0, 0x10, 0xf0, 0x80, 0xf0,  0xf0, 0x10, 0xf0, 0x10, 0xf0,
+    0x90, 0x90, 0xf0, 0x10, 0x10,  0xf0, 0x80, 0xf0, 0x10, 0xf0,  0xf0, 0x80, 0xf0, 0x90, 0xf0,  0xf0, 0x10, 0x20, 0x40, 0x40,
+    0xf0, 0x90, 0xf0, 0x90, 0xf0,  0xf0, 0x90, 0xf0, 0x10, 0xf0,  0xf0, 0x90, 0xf0, 0x90, 0x90,  0xe0, 0x90, 0xe0, 0x90, 0xe0,
+    0xf0, 0x80, 0x80, 0x80, 0xf0,  0xe0, 0x90, 0x90, 0x90, 0xe0,  0xf0, 0x80, 0xf0, 0x80, 0xf0,  0xf0, 0x80, 0xf0, 0x80, 0x80];
+
+// SUPER-CHIP 1.1 only has big digits 0-9, A-F are taken from the default set
+const SCHIP_BIG_DIGITS: [u8; 100] = [
+    0x3c, 0x7e, 0xe7, 0xc3, 0xc3, 0xc3, 0xc3, 0xe7, 0x7e, 0x3c,  0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3c,
+    0x3e, 0x7f, 0xc3, 0x06, 0x0c, 0x18, 0x30, 0x60, 0xff, 0xff,  0x3c, 0x7e, 0xc3, 0x03, 0x0e, 0x0e, 0x03, 0xc3, 0x7e, 0x3c,
+    0x06, 0x0e, 0x1e, 0x36, 0x66, 0xc6, 0xff, 0xff, 0x06, 0x06,  0xff, 0xff, 0xc0, 0xc0, 0xfc, 0xfe, 0x03, 0xc3, 0x7e, 0x3c,
+    0x3e, 0x7c, 0xe0, 0xc0, 0xfc, 0xfe, 0xc3, 0xc3, 0x7e, 0x3c,  0xff, 0xff, 0x03, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x60, 0x60,
+    0x3c, 0x7e, 0xc3, 0xc3, 0x7e, 0x7e, 0xc3, 0xc3, 0x7e, 0x3c,  0x3c, 0x7e, 0xc3, 0xc3, 0x7f, 0x3f, 0x03, 0x03, 0x3e, 0x7c];
+
+impl Font {
+    pub const NAMES: [&'static str; 5] = ["default", "vip", "dream6800", "eti660", "schip"];
+    pub const LEN: usize = 80 + 160;
+
+    fn with_small(small: [u8; 80]) -> Self {
+        Self { small, big: *DEFAULT_BIG }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "vip" => Some(Self::with_small(VIP)),
+            "dream6800" => Some(Self::with_small(DREAM_6800)),
+            "eti660" => Some(Self::with_small(ETI_660)),
+            "schip" => {
+                let mut font = Self::with_small(SCHIP);
+                font.big[..SCHIP_BIG_DIGITS.len()].copy_from_slice(&SCHIP_BIG_DIGITS);
+                Some(font)
+            },
+            _ => None
+        }
+    }
+
+    /// reads 80 bytes of small digits, optionally followed by 160 bytes of big digits
+    pub fn from_file(filename: &str) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        File::open(filename)?.read_to_end(&mut data)?;
+        let mut font = Self::default();
+        match data.len() {
+            80 => font.small.copy_from_slice(&data),
+            Self::LEN => {
+                font.small.copy_from_slice(&data[..80]);
+                font.big.copy_from_slice(&data[80..]);
+            },
+            n => return Err(Error::new(ErrorKind::InvalidData, format!("font files have 80 or {} bytes, not {}", Self::LEN, n)))
+        }
+        Ok(font)
+    }
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Self { small: *DEFAULT_SMALL, big: *DEFAULT_BIG }
+    }
+}
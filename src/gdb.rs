@@ -0,0 +1,399 @@
+use std::collections::BTreeSet;
+use std::io::{self, prelude::*, ErrorKind};
+use std::net::TcpStream;
+
+use crate::state::{Chip8State, ExecError, StepOutcome};
+
+/// the registers as gdb numbers them: V0-VF, I, PC, SP (the stack depth), DT and ST
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 21;
+
+/// instructions between two looks at the connection for an interrupt while the machine runs
+const POLL_INTERVAL: u32 = 1024;
+
+/// why the machine stopped, sent to gdb as a stop reply
+#[derive(Debug,Clone,PartialEq)]
+pub enum Stop {
+    Trap,            // breakpoint or single step
+    Interrupted,     // ctrl-c in gdb
+    Fault(ExecError),
+    Exit             // 00FD
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Trap => "S05".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+            Stop::Fault(_) => "S04".to_string(), // SIGILL, the pc stays on the instruction
+            Stop::Exit => "W00".to_string()
+        }
+    }
+}
+
+/// what a packet asks the server to do
+#[derive(Debug,Clone,PartialEq)]
+pub enum Request {
+    Reply(String),
+    Step,
+    Continue,
+    Detach,
+    Kill
+}
+
+/// the machine side of the GDB remote serial protocol: registers, memory, software breakpoints,
+/// single steps and continuing, with a 60 Hz tick whenever a frame is done as in the headless mode
+pub struct GdbStub {
+    pub breakpoints: BTreeSet<u16>,
+    cycles_per_frame: u32,
+    frame_cycles: u32,
+    acks: bool,
+    killed: bool
+}
+
+impl GdbStub {
+    pub fn new(cycles_per_frame: u32) -> Self {
+        Self { breakpoints: BTreeSet::new(), cycles_per_frame, frame_cycles: 0, acks: true, killed: false }
+    }
+
+    /// answers a packet (without `$` and checksum) that does not run the machine
+    pub fn handle(&mut self, cas: &mut Chip8State, packet: &str) -> Request {
+        let reply = |text: &str| Request::Reply(text.to_string());
+        let error = || reply("E01");
+        let (kind, args) = packet.split_at(packet.char_indices().nth(1).map(|(n, _)| n).unwrap_or(packet.len()));
+        match kind {
+            "?" => reply("S05"),
+            "g" => Request::Reply((0..REGISTER_COUNT).map(|n| read_register(cas, n)).collect()),
+            "G" => {
+                let mut rest = args;
+                for n in 0..REGISTER_COUNT {
+                    let size = register_size(n);
+                    match (rest.get(..2*size).and_then(|hex| decode_le(hex, size)), rest.get(2*size..)) {
+                        (Some(value), Some(tail)) if write_register(cas, n, value) => rest = tail,
+                        _ => return error()
+                    }
+                }
+                reply("OK")
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTER_COUNT => Request::Reply(read_register(cas, n)),
+                _ => error()
+            },
+            "P" => match args.split_once('=').and_then(|(n, hex)| usize::from_str_radix(n, 16).ok().map(|n| (n, hex))) {
+                Some((n, hex)) if n < REGISTER_COUNT => match decode_le(hex, register_size(n)) {
+                    Some(value) if write_register(cas, n, value) => reply("OK"),
+                    _ => error()
+                },
+                _ => error()
+            },
+            "m" => match parse_range(args) {
+                Some((address, len)) if address < cas.cartridge.memory.len() => {
+                    let end = cas.cartridge.memory.len().min(address.saturating_add(len));
+                    Request::Reply(cas.cartridge.memory[address..end].iter().map(|b| format!("{:02x}", b)).collect())
+                },
+                _ => error()
+            },
+            "M" => match args.split_once(':').and_then(|(range, hex)| Some((parse_range(range)?, decode_bytes(hex)?))) {
+                Some(((address, len), bytes)) if bytes.len() == len && address.saturating_add(len) <= cas.cartridge.memory.len() => {
+                    cas.cartridge.memory[address..address + len].copy_from_slice(&bytes);
+                    reply("OK")
+                },
+                _ => error()
+            },
+            "Z" | "z" => match args.strip_prefix("0,").and_then(|a| a.split(',').next()).and_then(|a| u16::from_str_radix(a, 16).ok()) {
+                Some(address) => {
+                    if kind == "Z" { self.breakpoints.insert(address); } else { self.breakpoints.remove(&address); }
+                    reply("OK")
+                },
+                None => reply("") // only software breakpoints
+            },
+            "s" | "c" if !args.is_empty() => match u16::from_str_radix(args, 16) {
+                Ok(address) => {
+                    cas.pc = address;
+                    if kind == "s" { Request::Step } else { Request::Continue }
+                },
+                Err(_) => error()
+            },
+            "s" => Request::Step,
+            "c" => Request::Continue,
+            "D" => Request::Detach,
+            "k" => Request::Kill,
+            "H" => reply("OK"),
+            "T" => reply("OK"),
+            _ => self.query(packet)
+        }
+    }
+
+    /// whether gdb asked to end the machine rather than detaching from it
+    pub fn killed(&self) -> bool {
+        self.killed
+    }
+
+    fn query(&mut self, packet: &str) -> Request {
+        let reply = |text: &str| Request::Reply(text.to_string());
+        if packet.starts_with("qSupported") {
+            return reply("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+")
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(range) {
+                Some((offset, len)) => {
+                    let chunk = TARGET_XML.get(offset.min(TARGET_XML.len())..TARGET_XML.len().min(offset.saturating_add(len))).unwrap_or("");
+                    let more = offset.saturating_add(len) < TARGET_XML.len();
+                    Request::Reply(format!("{}{}", if more { "m" } else { "l" }, chunk))
+                },
+                None => reply("E01")
+            }
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.acks = false;
+                reply("OK")
+            },
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            "qSymbol::" => reply("OK"),
+            _ => reply("") // not supported
+        }
+    }
+
+    /// runs a single instruction, ticking the timers when a frame is done
+    pub fn step(&mut self, cas: &mut Chip8State) -> Result<(), Stop> {
+        let outcome = cas.step().map_err(Stop::Fault)?;
+        self.frame_cycles += 1;
+        match outcome {
+            StepOutcome::Exit => return Err(Stop::Exit),
+            StepOutcome::WaitingForVblank => self.frame_cycles = self.cycles_per_frame,
+            _ => {}
+        }
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.frame_cycles = 0;
+            cas.tick()
+        }
+        Ok(())
+    }
+
+    /// runs until a breakpoint, a fault or `interrupted` returns true
+    pub fn resume(&mut self, cas: &mut Chip8State, mut interrupted: impl FnMut() -> bool) -> Stop {
+        for n in 1u32.. {
+            if let Err(stop) = self.step(cas) { return stop }
+            if self.breakpoints.contains(&cas.pc) { return Stop::Trap }
+            if n.is_multiple_of(POLL_INTERVAL) && interrupted() { return Stop::Interrupted }
+        }
+        Stop::Interrupted
+    }
+
+    /// serves one gdb connection until it detaches, kills the machine or hangs up; faults are
+    /// returned to the caller for reporting after gdb has been told about them
+    pub fn serve(&mut self, cas: &mut Chip8State, stream: &mut TcpStream) -> io::Result<Vec<ExecError>> {
+        let mut faults = Vec::new();
+        self.acks = true; // every gdb starts out acknowledging packets
+        while let Some(packet) = read_packet(stream, self.acks)? {
+            let answer = match self.handle(cas, &packet) {
+                Request::Reply(text) => text,
+                Request::Step => self.step(cas).err().unwrap_or(Stop::Trap).reply(),
+                Request::Continue => {
+                    let stop = self.resume(cas, || interrupt_pending(stream));
+                    if let Stop::Fault(e) = &stop { faults.push(e.clone()) }
+                    stop.reply()
+                },
+                Request::Detach => {
+                    write_packet(stream, "OK")?;
+                    break
+                },
+                Request::Kill => {
+                    self.killed = true;
+                    break
+                }
+            };
+            write_packet(stream, &answer)?
+        }
+        Ok(faults)
+    }
+}
+
+fn register_size(n: usize) -> usize {
+    if n == 16 || n == 17 { 2 } else { 1 }
+}
+
+/// a register as little-endian hex, the way `g` and `p` send it
+fn read_register(cas: &Chip8State, n: usize) -> String {
+    let value = match n {
+        0..=15 => cas.v(n as u8) as u16,
+        16 => cas.i(),
+        17 => cas.pc,
+        18 => cas.stack().len() as u16,
+        19 => cas.delay_timer() as u16,
+        _ => cas.sound_timer() as u16
+    };
+    value.to_le_bytes()[..register_size(n)].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn write_register(cas: &mut Chip8State, n: usize, value: u16) -> bool {
+    match n {
+        0..=15 => cas.set_v(n as u8, value as u8),
+        16 => cas.set_i(value),
+        17 => cas.pc = value,
+        18 => return cas.set_stack_depth(value as usize).is_ok(),
+        19 => cas.set_delay_timer(value as u8),
+        _ => cas.set_sound_timer(value as u8)
+    }
+    true
+}
+
+fn decode_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) { return None }
+    (0..hex.len()).step_by(2).map(|n| u8::from_str_radix(hex.get(n..n + 2)?, 16).ok()).collect()
+}
+
+fn decode_le(hex: &str, size: usize) -> Option<u16> {
+    let bytes = decode_bytes(hex).filter(|b| b.len() == size)?;
+    Some(bytes.iter().rev().fold(0, |value, b| value << 8 | *b as u16))
+}
+
+/// `ADDR,LENGTH` in hex
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, len) = text.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+/// the next packet, acknowledged unless gdb turned acks off; `None` once gdb hangs up
+fn read_packet(stream: &mut (impl Read + Write), acks: bool) -> io::Result<Option<String>> {
+    loop {
+        // acks, nacks and interrupts of a machine that is stopped anyway are skipped
+        match read_byte(stream)? {
+            None => return Ok(None),
+            Some(b'$') => {},
+            Some(_) => continue
+        }
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(b) => data.push(b)
+            }
+        }
+        let sum = match (read_byte(stream)?, read_byte(stream)?) {
+            (Some(hi), Some(lo)) => u8::from_str_radix(&format!("{}{}", hi as char, lo as char), 16).ok(),
+            _ => return Ok(None)
+        };
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let valid = sum == Some(checksum(&data));
+        if acks {
+            stream.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        if valid { return Ok(Some(data)) }
+    }
+}
+
+fn read_byte(stream: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        return match stream.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => Err(e)
+        }
+    }
+}
+
+fn write_packet(stream: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+/// whether gdb sent ctrl-c (0x03) while the machine runs
+fn interrupt_pending(stream: &mut TcpStream) -> bool {
+    let mut byte = [0u8];
+    if stream.set_nonblocking(true).is_err() { return false }
+    let interrupted = match stream.read(&mut byte) {
+        Ok(1) => byte[0] == 0x03,
+        Ok(_) => true, // hung up, stop running
+        Err(e) => e.kind() != ErrorKind::WouldBlock
+    };
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cartridge, Quirks};
+
+    fn machine() -> Chip8State {
+        let cartridge = Cartridge::from_rom(&[0x60, 0x12, 0xa3, 0x45, 0x12, 0x04], Quirks::vip().memory_size).unwrap();
+        Chip8State::new(cartridge, Quirks::vip())
+    }
+
+    #[test]
+    fn reads_and_writes_registers_and_memory() {
+        let mut cas = machine();
+        let mut stub = GdbStub::new(1000);
+        for _ in 0..2 { stub.step(&mut cas).unwrap() }
+        let regs = format!("12{}4503{}000000", "00".repeat(15), "0402");
+        assert_eq!(stub.handle(&mut cas, "g"), Request::Reply(regs));
+        assert_eq!(stub.handle(&mut cas, "P3=7f"), Request::Reply("OK".to_string()));
+        assert_eq!(cas.v(3), 0x7f);
+        assert_eq!(stub.handle(&mut cas, "M300,2:beef"), Request::Reply("OK".to_string()));
+        assert_eq!(stub.handle(&mut cas, "m2ff,3"), Request::Reply("00beef".to_string()));
+        assert_eq!(stub.handle(&mut cas, "m1000,1"), Request::Reply("E01".to_string()));
+        // lengths that overflow the address are cut off at the end of memory
+        assert_eq!(stub.handle(&mut cas, "mfff,ffffffffffffffff"), Request::Reply("00".to_string()));
+        assert_eq!(stub.handle(&mut cas, "Mfff,ffffffffffffffff:00"), Request::Reply("E01".to_string()));
+        assert!(matches!(stub.handle(&mut cas, "qXfer:features:read:target.xml:10,ffffffffffffffff"), Request::Reply(r) if r.starts_with('l')));
+    }
+
+    #[test]
+    fn continues_to_a_breakpoint() {
+        let mut cas = machine();
+        let mut stub = GdbStub::new(1000);
+        assert_eq!(stub.handle(&mut cas, "Z0,204,2"), Request::Reply("OK".to_string()));
+        assert_eq!(stub.handle(&mut cas, "c"), Request::Continue);
+        assert_eq!(stub.resume(&mut cas, || false), Stop::Trap);
+        assert_eq!(cas.pc, 0x204);
+        assert_eq!(stub.resume(&mut cas, || false), Stop::Trap); // 1204 jumps to itself
+    }
+
+    #[test]
+    fn frames_packets_with_checksums() {
+        let mut wire = io::Cursor::new(b"+$m200,2#5d$g#00$g#67".to_vec());
+        assert_eq!(read_packet(&mut wire, false).unwrap().as_deref(), Some("m200,2"));
+        assert_eq!(read_packet(&mut wire, false).unwrap().as_deref(), Some("g")); // the bad checksum was skipped
+        assert_eq!(read_packet(&mut wire, false).unwrap(), None);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::movie::hash;
+use crate::state::TermDisplay;
+
+/// the display as a plain PBM bitmap, a pixel is black when it is set in any plane
+pub fn pbm(display: &TermDisplay) -> Vec<u8> {
+    let mut out = format!("P4\n{} {}\n", display.width(), display.height()).into_bytes();
+    for y in 0..display.height() {
+        let row: Vec<bool> = (0..display.width()).map(|x| display.pixel(x, y) != 0).collect();
+        out.extend(row.chunks(8).map(|px| px.iter().enumerate().fold(0u8, |b, (n, set)| b | (*set as u8) << (7 - n))));
+    }
+    out
+}
+
+/// the display as an RGB PNG, one image pixel per display pixel, coloured by `palette`
+pub fn png(display: &TermDisplay, palette: &[u32; 4]) -> Vec<u8> {
+    let (w, h) = (display.width() as u32, display.height() as u32);
+    let mut raw = Vec::with_capacity((h * (1 + 3*w)) as usize);
+    for y in 0..display.height() {
+        raw.push(0); // no filter
+        for x in 0..display.width() {
+            let rgb = palette[(display.pixel(x, y) & 0x3) as usize];
+            raw.extend_from_slice(&rgb.to_be_bytes()[1..]);
+        }
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&w.to_be_bytes());
+    ihdr.extend_from_slice(&h.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits per channel, RGB, deflate, no filter, no interlace
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut out, b"IHDR", &ihdr);
+    chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// hash of the resolution and all pixels, equal displays have equal hashes
+pub fn framebuffer_hash(display: &TermDisplay) -> u64 {
+    let mut bytes = vec![display.width(), display.height()];
+    for y in 0..display.height() {
+        bytes.extend((0..display.width()).map(|x| display.pixel(x, y)));
+    }
+    hash(&bytes)
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// zlib stream of uncompressed deflate blocks, the images are tiny anyway
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(0xffff).collect() };
+    for (n, block) in blocks.iter().enumerate() {
+        out.push((n + 1 == blocks.len()) as u8); // final block flag, stored
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |c, _| if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cartridge, Chip8State, Quirks};
+
+    /// a low resolution display with only the top left pixel set
+    fn display() -> TermDisplay {
+        let rom = [0xa2, 0x06, 0xd0, 0x01, 0x12, 0x04, 0x80];
+        let mut cas = Chip8State::new(Cartridge::from_rom(&rom, Quirks::chip48().memory_size).unwrap(), Quirks::chip48());
+        cas.step().unwrap();
+        cas.step().unwrap();
+        cas.display
+    }
+
+    #[test]
+    fn pbm_packs_eight_pixels_per_byte() {
+        let mut expected = b"P4\n64 32\n".to_vec();
+        expected.push(0x80);
+        expected.extend(vec![0; 32 * 8 - 1]);
+        assert_eq!(pbm(&display()), expected);
+    }
+
+    #[test]
+    fn png_has_a_valid_header_and_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(&zlib_stored(b"Wikipedia")[2..], b"\x01\x09\x00\xf6\xffWikipedia\x11\xe6\x03\x98");
+
+        let png = png(&display(), &TermDisplay::DEFAULT_PALETTE);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[8..16], b"\x00\x00\x00\x0dIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 64, 0, 0, 0, 32, 8, 2, 0, 0, 0]);
+        assert_eq!(&png[29..33], &crc32(&png[12..29]).to_be_bytes());
+        // the first row starts with its filter byte and the plane 1 colour
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        assert_eq!(&idat[8..10], &[0x78, 0x01]);
+        assert_eq!(&idat[15..19], &[0, 0xf0, 0xff, 0xff]);
+        assert_eq!(&png[png.len() - 12..], b"\x00\x00\x00\x00IEND\xae\x42\x60\x82");
+    }
+}
@@ -17,7 +17,7 @@ impl fmt::Display for Varset {
   }
 }
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum Operation {
     Set,
     IncrementNoCarry,
@@ -29,18 +29,28 @@ pub enum Operation {
     BitAnd,
     BitXor,
     BitshiftAndStore,
+    BitshiftLeftAndStore,
     DecrementAndFlip,
-    SpriteMultiply
+    SpriteMultiply,
+    BigSpriteMultiply
 }
 
-#[derive(Clone)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum Instruction {
     RCARoutine(u16),
 
     ClearDraw,
-    DrawSpriteXYH(Varset,Varset,u8), //ClearDraw = DrawXYH(0,0,0)
+    DrawSpriteXYH(Varset,Varset,u8), // height 0 draws a 16x16 sprite
+    SetHighResolution(bool),
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    ExitInterpreter,
+    SelectPlanes(u8),
 
     GotoAdress(u16),
+    GotoAdressPlusV0(u16),
     RunSubroutineAtAdress(u16),
     ReturnFromSubroutine,
 
@@ -52,12 +62,35 @@ pub enum Instruction {
     VariableOnValue(Varset, u8, Operation),
     VariableOnVariable(Varset, Varset, Operation),
     SetITo(u16),
+    SetIToNextWord, // XO-CHIP: the address is stored in the following two bytes
     IOnVariable(Varset, Operation),
     StoreVarAsDecimalInPositionI(Varset),
     DumpVariablesUptoInPositionI(Varset),
     LoadVariablesUptoFromPositionI(Varset),
+    WaitForKeypressInto(Varset),
+    StoreFlagsUpto(Varset),
+    LoadFlagsUpto(Varset),
+    DumpVariablesRangeInPositionI(Varset, Varset),
+    LoadVariablesRangeFromPositionI(Varset, Varset),
+    LoadAudioPattern,
+    SetPitchTo(Varset),
+}
+
+/// an opcode that does not map to any instruction
+#[derive(Debug,Clone,PartialEq)]
+pub struct DecodeError {
+    pub opcode: u16,
+    pub reason: &'static str
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} opcode {:#06x} not implemented", self.reason, self.opcode)
+  }
 }
 
+impl std::error::Error for DecodeError {}
+
 fn get_0x00(opcode: u16) -> Varset {
     Varset::V( ((opcode & 0x0f00) >> 8) as u8 )
 }
@@ -83,8 +116,16 @@ impl fmt::Display for Instruction {
     match self {
       Instruction::RCARoutine(routine) => write!(f, "RCA routine {:#05x}", routine),
       Instruction::ClearDraw => write!(f, "clear display"),
+      Instruction::SetHighResolution(hires) => write!(f, "switch to {} resolution", if *hires { "high" } else { "low" }),
+      Instruction::ScrollDown(n) => write!(f, "scroll display down by {}", n),
+      Instruction::ScrollUp(n) => write!(f, "scroll display up by {}", n),
+      Instruction::SelectPlanes(planes) => write!(f, "select drawing planes {:#04b}", planes),
+      Instruction::ScrollRight => write!(f, "scroll display right by 4"),
+      Instruction::ScrollLeft => write!(f, "scroll display left by 4"),
+      Instruction::ExitInterpreter => write!(f, "exit interpreter"),
       Instruction::ReturnFromSubroutine => write!(f, "return from subroutine"),
       Instruction::GotoAdress(address) => write!(f, "goto address {:#05x}", address),
+      Instruction::GotoAdressPlusV0(address) => write!(f, "goto address {:#05x} + {}", address, Varset::V(0)),
       Instruction::RunSubroutineAtAdress(address) => write!(f, "run subroutine at {:#05x}", address),
       Instruction::SkipNextIfVarEq(var, val) => write!(f, "if ({} == {:#04x}) skip next", var, val),
       Instruction::SkipNextIfVarNeq(var, val) => write!(f, "if ({} != {:#04x}) skip next", var, val),
@@ -92,8 +133,10 @@ impl fmt::Display for Instruction {
           Operation::Set => write!(f, "set {} to {:#04x}", var, val),
           Operation::IncrementNoCarry => write!(f, "increment {} by {:#04x} ignoring carry", var, val),
           Operation::Randomize => write!(f, "randomize {} using {:#04x}", var, val),
-          _ => panic!("display of operation not supported")
+          _ => write!(f, "{:?} {} using {:#04x}", op, var, val)
       },
+      Instruction::SkipNextIfVarsEq(varx, Varset::Keyboard) => write!(f, "if (key {} is pressed) skip next", varx),
+      Instruction::SkipNextIfVarsNeq(varx, Varset::Keyboard) => write!(f, "if (key {} is not pressed) skip next", varx),
       Instruction::SkipNextIfVarsEq(varx, vary) => write!(f, "if ({} == {}) skip next", varx, vary),
       Instruction::SkipNextIfVarsNeq(varx, vary) => write!(f, "if ({} != {}) skip next", varx, vary),
       Instruction::VariableOnVariable(varx, vary, op) => match op {
@@ -106,75 +149,184 @@ impl fmt::Display for Instruction {
           Operation::BitOr => write!(f, "bitwise Or on {} using {} as second input", varx, vary),
           Operation::BitXor => write!(f, "bitwise XOr on {} using {} as second input", varx, vary),
           Operation::BitAnd => write!(f, "bitwise And on {} using {} as second input", varx, vary),
-          Operation::BitshiftAndStore => write!(f, "bitshift {} and store carry on {}", varx, vary),
-          _ => panic!("display of operation not supported")
+          Operation::BitshiftAndStore => write!(f, "bitshift {} right and store carry on {}", varx, vary),
+          Operation::BitshiftLeftAndStore => write!(f, "bitshift {} left and store carry on {}", varx, vary),
+          _ => write!(f, "{:?} {} using {}", op, varx, vary)
       },
       Instruction::DrawSpriteXYH(varx,vary, h) => write!(f, "draw sprite at {},{} with height {}", varx, vary, h),
       Instruction::SetITo(num) => write!(f,"set I to {}", num),
+      Instruction::SetIToNextWord => write!(f,"set I to the following 16-bit address"),
       Instruction::IOnVariable(vs, op) => match op {
           Operation::SpriteMultiply => write!(f,"set I to address for sprite {}", vs),
+          Operation::BigSpriteMultiply => write!(f,"set I to address for big sprite {}", vs),
           Operation::IncrementNoCarry => write!(f, "increment I by {}", vs),
-          _ => panic!("display of operation not supported")
+          _ => write!(f, "{:?} I using {}", op, vs)
       },
       Instruction::StoreVarAsDecimalInPositionI(vs) => write!(f, "store {} at decimal starting from memory position I", vs),
       Instruction::DumpVariablesUptoInPositionI(vs) => write!(f, "dump {}--{} to memory position I", Varset::V(0), vs),
-      Instruction::LoadVariablesUptoFromPositionI(vs ) => write!(f, "load {}--{} from memory position I", Varset::V(0), vs)
+      Instruction::LoadVariablesUptoFromPositionI(vs ) => write!(f, "load {}--{} from memory position I", Varset::V(0), vs),
+      Instruction::WaitForKeypressInto(vs) => write!(f, "wait for key press and release, store the key in {}", vs),
+      Instruction::StoreFlagsUpto(vs) => write!(f, "store {}--{} in flag registers", Varset::V(0), vs),
+      Instruction::LoadFlagsUpto(vs) => write!(f, "load {}--{} from flag registers", Varset::V(0), vs),
+      Instruction::DumpVariablesRangeInPositionI(va, vb) => write!(f, "dump {}--{} to memory position I", va, vb),
+      Instruction::LoadVariablesRangeFromPositionI(va, vb) => write!(f, "load {}--{} from memory position I", va, vb),
+      Instruction::LoadAudioPattern => write!(f, "load audio pattern from memory position I"),
+      Instruction::SetPitchTo(vs) => write!(f, "set audio pitch to {}", vs)
     }
   }
 }
 
-// pub fn changes_display(instruction: Instruction) -> bool {
-//   match instruction {
-//     Instruction::ClearDraw => true,
-//     Instruction::DrawSpriteXYH(_,_ ,_ ) => true,
-//     _ => false
-//   }
-// }
-
-pub fn from_opcode(opcode: u16) -> Instruction {
-  match opcode {
-    0x00e0 => Instruction::ClearDraw,
-    0x00ee => Instruction::ReturnFromSubroutine,
-    _ => match opcode & 0xf000 {
-      0x0000 => Instruction::RCARoutine(get_0nnn(opcode)), // this is usually just empty space in the ROM file, 0 maps to idle
-      0x1000 => Instruction::GotoAdress(get_0nnn(opcode)),
-      0x2000 => Instruction::RunSubroutineAtAdress(get_0nnn(opcode)),
-      0x3000 => Instruction::SkipNextIfVarEq(get_0x00(opcode), get_00nn(opcode)),
-      0x4000 => Instruction::SkipNextIfVarNeq(get_0x00(opcode), get_00nn(opcode)),
-      0x6000 => Instruction::VariableOnValue(get_0x00(opcode), get_00nn(opcode), Operation::Set),
-      0x7000 => Instruction::VariableOnValue(get_0x00(opcode), get_00nn(opcode), Operation::IncrementNoCarry),
-      0x8000 => Instruction::VariableOnVariable(get_0x00(opcode), get_00y0(opcode), match opcode & 0x000f {
-           0 => Operation::Set,
-           1 => Operation::BitOr,
-           2 => Operation::BitAnd,
-           3 => Operation::BitXor,
-           4 => Operation::IncrementWithCarry,
-           5 => Operation::DecrementWithBorrow,
-           6 => Operation::BitshiftAndStore,
-           7 => Operation::DecrementAndFlip,
-           _ => panic!("var-on-var opcode {:#06x} not implemented", opcode)
-           }),
-      0x9000 => Instruction::SkipNextIfVarsNeq(get_0x00(opcode), get_00y0(opcode)),
-      0xa000 => Instruction::SetITo(get_0nnn(opcode)),
-      0xc000 => Instruction::VariableOnValue(get_0x00(opcode), get_00nn(opcode), Operation::Randomize),
-      0xd000 => Instruction::DrawSpriteXYH(get_0x00(opcode), get_00y0(opcode), get_000n(opcode)),
-      0xe000 => match opcode & 0x00ff {
-        0x9e => Instruction::SkipNextIfVarsEq(get_0x00(opcode), Varset::Keyboard),
-        0xa1 => Instruction::SkipNextIfVarsNeq(get_0x00(opcode), Varset::Keyboard),
-           _ => panic!("keyboard detector opcode {:#06x} not implemented", opcode)
-           }
-      0xf000 => match opcode & 0x00ff {
-        0x07 => Instruction::VariableOnVariable(get_0x00(opcode), Varset::DelayTimer, Operation::Set),
-        0x15 => Instruction::VariableOnVariable(Varset::DelayTimer, get_0x00(opcode), Operation::Set),
-        0x18 => Instruction::VariableOnVariable(Varset::SoundTimer, get_0x00(opcode), Operation::Set),
-        0x1e => {let vx = get_0x00(opcode); if vx == Varset::V(0xf) { panic!("vf not allowed here") }; Instruction::IOnVariable(get_0x00(opcode), Operation::IncrementNoCarry) },
-        0x29 => Instruction::IOnVariable(get_0x00(opcode), Operation::SpriteMultiply),
-        0x33 => Instruction::StoreVarAsDecimalInPositionI(get_0x00(opcode)),
-        0x55 => Instruction::DumpVariablesUptoInPositionI(get_0x00(opcode)),
-        0x65 => Instruction::LoadVariablesUptoFromPositionI(get_0x00(opcode)),
-           _ => panic!("special-function opcode {:#06x} not implemented", opcode)
-           }
-      _ => panic!("opcode {:#06x} not implemented", opcode)
+/// the instruction of `opcode`, looked up in `ENCODINGS` so that the assembler encodes exactly what runs
+pub fn from_opcode(opcode: u16) -> Result<Instruction, DecodeError> {
+  match encoding_of(opcode) {
+    Some(e) => Ok((e.decode)(opcode)),
+    None => Err(DecodeError { opcode, reason: match opcode & 0xf000 {
+      0x5000 => "var-equality",
+      0x8000 => "var-on-var",
+      0x9000 => "var-inequality",
+      0xe000 => "keyboard detector",
+      0xf000 if opcode & 0x00ff == 0x01 => "plane select",
+      0xf000 => "special-function",
+      _ => "unknown"
+    }})
+  }
+}
+
+/// an operand in assembly syntax, and where its value sits in the opcode
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Arg {
+    X,                // Vx, bits 0x0f00
+    Y,                // Vy, bits 0x00f0
+    Byte,             // bits 0x00ff
+    Nibble,           // bits 0x000f
+    Addr,             // bits 0x0fff
+    Planes,           // plane mask 0 -- 3, bits 0x0f00
+    Long,             // `LONG addr`, the word after the opcode
+    Word(&'static str) // a fixed operand like I, [I] or DT
+}
+
+impl Arg {
+  fn mask(&self) -> u16 {
+    match self {
+      Arg::X | Arg::Planes => 0x0f00,
+      Arg::Y => 0x00f0,
+      Arg::Byte => 0x00ff,
+      Arg::Nibble => 0x000f,
+      Arg::Addr => 0x0fff,
+      Arg::Long | Arg::Word(_) => 0
     }
   }
+
+  /// the value of the operand in `opcode`
+  pub fn get(&self, opcode: u16) -> u16 {
+    let mask = self.mask();
+    if mask == 0 { 0 } else { (opcode & mask) >> mask.trailing_zeros() }
+  }
+
+  /// `opcode` with the operand set to `value`, if it fits
+  pub fn put(&self, opcode: u16, value: u16) -> Option<u16> {
+    let mask = self.mask();
+    if mask == 0 { return None }
+    let limit = if *self == Arg::Planes { 3 } else { mask >> mask.trailing_zeros() };
+    if value > limit { return None }
+    Some(opcode | value << mask.trailing_zeros())
+  }
+}
+
+/// how an instruction is written in Cowgod's assembly syntax (with the SUPER-CHIP and XO-CHIP
+/// additions) and which opcodes it stands for: those with `opcode & mask == bits`
+#[derive(Debug,Clone)]
+pub struct Encoding {
+  pub mnemonic: &'static str,
+  pub args: &'static [Arg],
+  pub bits: u16,
+  pub mask: u16,
+  decode: fn(u16) -> Instruction
+}
+
+impl Encoding {
+  /// instructions with a `LONG` operand take up the following word too
+  pub fn size(&self) -> u16 {
+    if self.args.contains(&Arg::Long) { 4 } else { 2 }
+  }
+}
+
+const fn enc(mnemonic: &'static str, args: &'static [Arg], bits: u16, mask: u16, decode: fn(u16) -> Instruction) -> Encoding {
+  Encoding { mnemonic, args, bits, mask, decode }
+}
+
+/// every instruction, the more specific opcodes first; `from_opcode` decodes with it and the assembler encodes with it
+pub const ENCODINGS: &[Encoding] = &[
+  enc("CLS", &[], 0x00e0, 0xffff, |_| Instruction::ClearDraw),
+  enc("RET", &[], 0x00ee, 0xffff, |_| Instruction::ReturnFromSubroutine),
+  enc("SCR", &[], 0x00fb, 0xffff, |_| Instruction::ScrollRight),
+  enc("SCL", &[], 0x00fc, 0xffff, |_| Instruction::ScrollLeft),
+  enc("EXIT", &[], 0x00fd, 0xffff, |_| Instruction::ExitInterpreter),
+  enc("LOW", &[], 0x00fe, 0xffff, |_| Instruction::SetHighResolution(false)),
+  enc("HIGH", &[], 0x00ff, 0xffff, |_| Instruction::SetHighResolution(true)),
+  enc("LD", &[Arg::Word("I"), Arg::Long], 0xf000, 0xffff, |_| Instruction::SetIToNextWord),
+  enc("AUDIO", &[], 0xf002, 0xffff, |_| Instruction::LoadAudioPattern),
+  enc("SCD", &[Arg::Nibble], 0x00c0, 0xfff0, |op| Instruction::ScrollDown(get_000n(op))),
+  enc("SCU", &[Arg::Nibble], 0x00d0, 0xfff0, |op| Instruction::ScrollUp(get_000n(op))),
+  enc("SYS", &[Arg::Addr], 0x0000, 0xf000, |op| Instruction::RCARoutine(get_0nnn(op))),
+  enc("JP", &[Arg::Addr], 0x1000, 0xf000, |op| Instruction::GotoAdress(get_0nnn(op))),
+  enc("CALL", &[Arg::Addr], 0x2000, 0xf000, |op| Instruction::RunSubroutineAtAdress(get_0nnn(op))),
+  enc("SE", &[Arg::X, Arg::Byte], 0x3000, 0xf000, |op| Instruction::SkipNextIfVarEq(get_0x00(op), get_00nn(op))),
+  enc("SNE", &[Arg::X, Arg::Byte], 0x4000, 0xf000, |op| Instruction::SkipNextIfVarNeq(get_0x00(op), get_00nn(op))),
+  enc("SE", &[Arg::X, Arg::Y], 0x5000, 0xf00f, |op| Instruction::SkipNextIfVarsEq(get_0x00(op), get_00y0(op))),
+  enc("SAVE", &[Arg::X, Arg::Y], 0x5002, 0xf00f, |op| Instruction::DumpVariablesRangeInPositionI(get_0x00(op), get_00y0(op))),
+  enc("LOAD", &[Arg::X, Arg::Y], 0x5003, 0xf00f, |op| Instruction::LoadVariablesRangeFromPositionI(get_0x00(op), get_00y0(op))),
+  enc("LD", &[Arg::X, Arg::Byte], 0x6000, 0xf000, |op| Instruction::VariableOnValue(get_0x00(op), get_00nn(op), Operation::Set)),
+  enc("ADD", &[Arg::X, Arg::Byte], 0x7000, 0xf000, |op| Instruction::VariableOnValue(get_0x00(op), get_00nn(op), Operation::IncrementNoCarry)),
+  enc("LD", &[Arg::X, Arg::Y], 0x8000, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::Set)),
+  enc("OR", &[Arg::X, Arg::Y], 0x8001, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::BitOr)),
+  enc("AND", &[Arg::X, Arg::Y], 0x8002, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::BitAnd)),
+  enc("XOR", &[Arg::X, Arg::Y], 0x8003, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::BitXor)),
+  enc("ADD", &[Arg::X, Arg::Y], 0x8004, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::IncrementWithCarry)),
+  enc("SUB", &[Arg::X, Arg::Y], 0x8005, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::DecrementWithBorrow)),
+  enc("SHR", &[Arg::X, Arg::Y], 0x8006, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::BitshiftAndStore)),
+  enc("SUBN", &[Arg::X, Arg::Y], 0x8007, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::DecrementAndFlip)),
+  enc("SHL", &[Arg::X, Arg::Y], 0x800e, 0xf00f, |op| Instruction::VariableOnVariable(get_0x00(op), get_00y0(op), Operation::BitshiftLeftAndStore)),
+  enc("SNE", &[Arg::X, Arg::Y], 0x9000, 0xf00f, |op| Instruction::SkipNextIfVarsNeq(get_0x00(op), get_00y0(op))),
+  enc("LD", &[Arg::Word("I"), Arg::Addr], 0xa000, 0xf000, |op| Instruction::SetITo(get_0nnn(op))),
+  enc("JP", &[Arg::Word("V0"), Arg::Addr], 0xb000, 0xf000, |op| Instruction::GotoAdressPlusV0(get_0nnn(op))),
+  enc("RND", &[Arg::X, Arg::Byte], 0xc000, 0xf000, |op| Instruction::VariableOnValue(get_0x00(op), get_00nn(op), Operation::Randomize)),
+  enc("DRW", &[Arg::X, Arg::Y, Arg::Nibble], 0xd000, 0xf000, |op| Instruction::DrawSpriteXYH(get_0x00(op), get_00y0(op), get_000n(op))),
+  enc("SKP", &[Arg::X], 0xe09e, 0xf0ff, |op| Instruction::SkipNextIfVarsEq(get_0x00(op), Varset::Keyboard)),
+  enc("SKNP", &[Arg::X], 0xe0a1, 0xf0ff, |op| Instruction::SkipNextIfVarsNeq(get_0x00(op), Varset::Keyboard)),
+  enc("PLANE", &[Arg::Planes], 0xf001, 0xf0ff, |op| Instruction::SelectPlanes(Arg::Planes.get(op) as u8)),
+  enc("LD", &[Arg::X, Arg::Word("DT")], 0xf007, 0xf0ff, |op| Instruction::VariableOnVariable(get_0x00(op), Varset::DelayTimer, Operation::Set)),
+  enc("LD", &[Arg::X, Arg::Word("K")], 0xf00a, 0xf0ff, |op| Instruction::WaitForKeypressInto(get_0x00(op))),
+  enc("LD", &[Arg::Word("DT"), Arg::X], 0xf015, 0xf0ff, |op| Instruction::VariableOnVariable(Varset::DelayTimer, get_0x00(op), Operation::Set)),
+  enc("LD", &[Arg::Word("ST"), Arg::X], 0xf018, 0xf0ff, |op| Instruction::VariableOnVariable(Varset::SoundTimer, get_0x00(op), Operation::Set)),
+  enc("ADD", &[Arg::Word("I"), Arg::X], 0xf01e, 0xf0ff, |op| Instruction::IOnVariable(get_0x00(op), Operation::IncrementNoCarry)),
+  enc("LD", &[Arg::Word("F"), Arg::X], 0xf029, 0xf0ff, |op| Instruction::IOnVariable(get_0x00(op), Operation::SpriteMultiply)),
+  enc("LD", &[Arg::Word("HF"), Arg::X], 0xf030, 0xf0ff, |op| Instruction::IOnVariable(get_0x00(op), Operation::BigSpriteMultiply)),
+  enc("LD", &[Arg::Word("B"), Arg::X], 0xf033, 0xf0ff, |op| Instruction::StoreVarAsDecimalInPositionI(get_0x00(op))),
+  enc("PITCH", &[Arg::X], 0xf03a, 0xf0ff, |op| Instruction::SetPitchTo(get_0x00(op))),
+  enc("LD", &[Arg::Word("[I]"), Arg::X], 0xf055, 0xf0ff, |op| Instruction::DumpVariablesUptoInPositionI(get_0x00(op))),
+  enc("LD", &[Arg::X, Arg::Word("[I]")], 0xf065, 0xf0ff, |op| Instruction::LoadVariablesUptoFromPositionI(get_0x00(op))),
+  enc("LD", &[Arg::Word("R"), Arg::X], 0xf075, 0xf0ff, |op| Instruction::StoreFlagsUpto(get_0x00(op))),
+  enc("LD", &[Arg::X, Arg::Word("R")], 0xf085, 0xf0ff, |op| Instruction::LoadFlagsUpto(get_0x00(op))),
+];
+
+/// the encoding of `opcode`, if it is an instruction
+pub fn encoding_of(opcode: u16) -> Option<&'static Encoding> {
+  ENCODINGS.iter().find(|e| opcode & e.mask == e.bits)
+    .filter(|e| !e.args.contains(&Arg::Planes) || Arg::Planes.get(opcode) <= 3)
+}
+
+/// `opcode` in assembly syntax; `long` is the word after it, `address` names jump targets and the like
+pub fn mnemonic(opcode: u16, long: u16, address: impl Fn(u16) -> String) -> Option<String> {
+  let e = encoding_of(opcode)?;
+  let args: Vec<String> = e.args.iter().map(|arg| match arg {
+    Arg::X => format!("V{:X}", Arg::X.get(opcode)),
+    Arg::Y => format!("V{:X}", Arg::Y.get(opcode)),
+    Arg::Byte => format!("{:#04x}", Arg::Byte.get(opcode)),
+    Arg::Nibble | Arg::Planes => arg.get(opcode).to_string(),
+    Arg::Addr => address(Arg::Addr.get(opcode)),
+    Arg::Long => format!("LONG {}", address(long)),
+    Arg::Word(w) => w.to_string()
+  }).collect();
+  Some(if args.is_empty() { e.mnemonic.to_string() } else { format!("{} {}", e.mnemonic, args.join(", ")) })
 }
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::fs;
+
+/// emulator functions that can be bound to host keys next to the 16 hex keys
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Action {
+    Pause,     // stops or resumes the emulation
+    Reset,     // restarts the ROM
+    Step,      // executes a single instruction while paused
+    Rewind,    // runs backwards while held down
+    SaveState(u8), // into one of the numbered slots
+    LoadState(u8),
+    Quit
+}
+
+impl Action {
+    pub const NAMES: [&'static str; 7] = ["pause", "reset", "step", "rewind", "save-state-N", "load-state-N", "quit"];
+    pub const SLOTS: u8 = 4;
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let slot = |n: &str| n.parse().ok().filter(|s| (1..=Self::SLOTS).contains(s));
+        match name {
+            "pause" => Some(Action::Pause),
+            "reset" => Some(Action::Reset),
+            "step" => Some(Action::Step),
+            "rewind" => Some(Action::Rewind),
+            "quit" => Some(Action::Quit),
+            _ => match (name.strip_prefix("save-state-"), name.strip_prefix("load-state-")) {
+                (Some(n), _) => slot(n).map(Action::SaveState),
+                (_, Some(n)) => slot(n).map(Action::LoadState),
+                _ => None
+            }
+        }
+    }
+}
+
+/// layout of the COSMAC VIP keypad, row by row
+const KEYPAD: [u8; 16] = [0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF];
+
+/// host keys on the left hand side of each layout that take the place of `KEYPAD`
+const QWERTY: [&str; 16] = ["1", "2", "3", "4", "Q", "W", "E", "R", "A", "S", "D", "F", "Z", "X", "C", "V"];
+const AZERTY: [&str; 16] = ["1", "2", "3", "4", "A", "Z", "E", "R", "Q", "S", "D", "F", "W", "X", "C", "V"];
+const QWERTZ: [&str; 16] = ["1", "2", "3", "4", "Q", "W", "E", "R", "A", "S", "D", "F", "Y", "X", "C", "V"];
+const DVORAK: [&str; 16] = ["1", "2", "3", "4", "Apostrophe", "Comma", "Period", "P", "A", "O", "E", "U", "Semicolon", "Q", "J", "K"];
+
+/// which host key presses which hex key or triggers which action
+///
+/// Host keys are named like `minifb::Key` (`A`, `Key1` or just `1`, `Space`, `F5`, `NumPad0`, ...),
+/// case does not matter.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Keymap {
+    keys: HashMap<String, u8>,
+    actions: HashMap<String, Action>
+}
+
+impl Keymap {
+    pub const PRESETS: [&'static str; 4] = ["qwerty", "azerty", "qwertz", "dvorak"];
+
+    /// one of `PRESETS` with the default action keys
+    pub fn preset(name: &str) -> Option<Self> {
+        let layout = match name {
+            "qwerty" => QWERTY,
+            "azerty" => AZERTY,
+            "qwertz" => QWERTZ,
+            "dvorak" => DVORAK,
+            _ => return None
+        };
+        let mut keymap = Self { keys: HashMap::new(), actions: HashMap::new() };
+        for (host, hex) in layout.iter().zip(KEYPAD.iter()) {
+            keymap.bind_key(host, *hex);
+        }
+        keymap.bind_action("F1", Action::Pause);
+        keymap.bind_action("F2", Action::Step);
+        keymap.bind_action("F3", Action::Reset);
+        keymap.bind_action("Backspace", Action::Rewind);
+        for slot in 1..=Action::SLOTS {
+            keymap.bind_action(&format!("F{}", 4 + slot), Action::SaveState(slot));
+            keymap.bind_action(&format!("F{}", 8 + slot), Action::LoadState(slot));
+        }
+        keymap.bind_action("Escape", Action::Quit);
+        Some(keymap)
+    }
+
+    /// lower case and `Key` prefix for digits, so that `1`, `key1` and `Key1` are the same key
+    fn normalize(host: &str) -> String {
+        let host = host.to_lowercase();
+        match host.len() == 1 && host.as_bytes()[0].is_ascii_digit() {
+            true => format!("key{}", host),
+            false => host
+        }
+    }
+
+    /// makes `host` press hex key `hex`, replacing what it was bound to before
+    pub fn bind_key(&mut self, host: &str, hex: u8) {
+        let host = Self::normalize(host);
+        self.actions.remove(&host);
+        self.keys.insert(host, hex & 0xf);
+    }
+
+    /// makes `host` trigger `action`, replacing what it was bound to before
+    pub fn bind_action(&mut self, host: &str, action: Action) {
+        let host = Self::normalize(host);
+        self.keys.remove(&host);
+        self.actions.retain(|_, a| *a != action);
+        self.actions.insert(host, action);
+    }
+
+    pub fn hex_key(&self, host: &str) -> Option<u8> {
+        self.keys.get(&Self::normalize(host)).copied()
+    }
+
+    pub fn action(&self, host: &str) -> Option<Action> {
+        self.actions.get(&Self::normalize(host)).copied()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::preset("qwerty").unwrap()
+    }
+}
+
+/// a single `NAME = VALUE` line of a keymap file and where it appeared
+#[derive(Debug,Clone)]
+struct Entry {
+    line: usize,
+    name: String,
+    value: String
+}
+
+/// contents of a keymap file, a small subset of TOML:
+///
+/// ```toml
+/// preset = "azerty"      # layout the bindings below start from
+///
+/// [keys]                 # host key = hex key
+/// Space = 5
+///
+/// [actions]              # action = host key
+/// pause = "P"
+///
+/// [rom."pong.ch8"]       # only for ROMs with this file name
+/// preset = "qwerty"
+/// Up = 1
+/// Down = 4
+/// ```
+#[derive(Debug,Clone)]
+pub struct KeymapConfig {
+    global: Vec<Entry>,
+    actions: Vec<Entry>,
+    roms: Vec<(String, Vec<Entry>)>
+}
+
+impl KeymapConfig {
+    pub fn from_file(filename: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        enum Section { Keys, Actions, Rom(usize) }
+        let mut config = Self { global: Vec::new(), actions: Vec::new(), roms: Vec::new() };
+        let mut section = Section::Keys;
+
+        for (n, raw) in text.lines().enumerate() {
+            let line = n + 1;
+            let text = match unquoted_find(raw, '#') {
+                Some(comment) => raw[..comment].trim(),
+                None => raw.trim()
+            };
+            if text.is_empty() { continue }
+
+            if let Some(header) = text.strip_prefix('[') {
+                let header = header.strip_suffix(']').ok_or(format!("line {}: unterminated section header", line))?.trim();
+                section = match header {
+                    "keys" => Section::Keys,
+                    "actions" => Section::Actions,
+                    _ => match header.strip_prefix("rom.") {
+                        Some(rom) => {
+                            config.roms.push((unquote(rom).to_string(), Vec::new()));
+                            Section::Rom(config.roms.len() - 1)
+                        },
+                        None => return Err(format!("line {}: unknown section [{}]", line, header))
+                    }
+                };
+                continue
+            }
+
+            let equals = unquoted_find(text, '=').ok_or(format!("line {}: expected NAME = VALUE", line))?;
+            let (name, value) = (&text[..equals], &text[equals + 1..]);
+            let entry = Entry { line, name: unquote(name.trim()).to_string(), value: unquote(value.trim()).to_string() };
+            match section {
+                Section::Keys => config.global.push(entry),
+                Section::Actions => config.actions.push(entry),
+                Section::Rom(r) => config.roms[r].1.push(entry)
+            }
+        }
+
+        // check everything now rather than when a ROM happens to use it
+        config.keymap_for(None)?;
+        for (rom, _) in &config.roms {
+            config.keymap_for(Some(rom))?;
+        }
+        Ok(config)
+    }
+
+    /// the global bindings, overridden by the section of the ROM at `rom_path` if there is one
+    pub fn keymap(&self, rom_path: &str) -> Keymap {
+        let rom = std::path::Path::new(rom_path).file_name().and_then(|f| f.to_str()).unwrap_or(rom_path);
+        self.keymap_for(Some(rom)).expect("keymap was checked when parsing")
+    }
+
+    fn keymap_for(&self, rom: Option<&str>) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        apply_keys(&mut keymap, &self.global)?;
+        for entry in &self.actions {
+            let action = Action::from_name(&entry.name)
+                .ok_or(format!("line {}: unknown action {}, choose one of {}", entry.line, entry.name, Action::NAMES.join(", ")))?;
+            keymap.bind_action(&entry.value, action);
+        }
+        if let Some(rom) = rom {
+            for (_, entries) in self.roms.iter().filter(|(name, _)| name == rom) {
+                apply_keys(&mut keymap, entries)?;
+            }
+        }
+        Ok(keymap)
+    }
+}
+
+/// `preset` starts over from a layout (actions are kept), any other entry binds a host key to a hex key
+fn apply_keys(keymap: &mut Keymap, entries: &[Entry]) -> Result<(), String> {
+    for entry in entries {
+        if entry.name == "preset" {
+            let preset = Keymap::preset(&entry.value)
+                .ok_or(format!("line {}: unknown preset {}, choose one of {}", entry.line, entry.value, Keymap::PRESETS.join(", ")))?;
+            keymap.keys = preset.keys;
+            let actions = &keymap.actions;
+            keymap.keys.retain(|host, _| !actions.contains_key(host));
+            continue
+        }
+        let digits = entry.value.strip_prefix("0x").unwrap_or(&entry.value);
+        match u8::from_str_radix(digits, 16) {
+            Ok(hex) if hex < 16 => keymap.bind_key(&entry.name, hex),
+            _ => return Err(format!("line {}: {} is not a hex key 0-F", entry.line, entry.value))
+        }
+    }
+    Ok(())
+}
+
+/// the position of the first `c` outside of double quotes
+fn unquoted_find(text: &str, c: char) -> Option<usize> {
+    let mut quoted = false;
+    for (n, t) in text.char_indices() {
+        match t {
+            '"' => quoted = !quoted,
+            t if t == c && !quoted => return Some(n),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(text: &str) -> &str {
+    text.strip_prefix('"').and_then(|t| t.strip_suffix('"')).unwrap_or(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_end_at_an_unquoted_hash() {
+        let config = KeymapConfig::parse(concat!(
+            "preset = \"azerty\"# no space before the comment\n",
+            "\"#\" = 5 # a quoted hash is a key name\n",
+            "\"=\" = 6\n",
+            "[rom.\"a # b.ch8\"] # and a section name\n",
+            "Space = 7\n")).unwrap();
+        let keymap = config.keymap("roms/a # b.ch8");
+        assert_eq!((keymap.hex_key("#"), keymap.hex_key("="), keymap.hex_key("Space")), (Some(5), Some(6), Some(7)));
+        assert_eq!((keymap.hex_key("A"), keymap.hex_key("Q")), (Some(4), Some(7))); // azerty swaps them
+        assert_eq!(config.keymap("other.ch8").hex_key("Space"), None);
+    }
+}
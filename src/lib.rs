@@ -0,0 +1,56 @@
+//! Emulator core for CHIP-8 and its SUPER-CHIP and XO-CHIP extensions.
+//!
+//! A [`Cartridge`] holds the memory with the loaded program, [`Chip8State`] runs it one
+//! instruction at a time and keeps the registers, [`TermDisplay`] and [`HexKeyboard`].
+//! The `minifb` window in [`window`] is only built with the `gui` feature.
+//!
+//! ```no_run
+//! use chip8_emu::{Cartridge, Chip8State, Quirks};
+//!
+//! let quirks = Quirks::vip();
+//! let cartridge = Cartridge::new("pong.rom".to_string(), quirks.memory_size).unwrap();
+//! let mut cas = Chip8State::new(cartridge, quirks);
+//! for _ in 0..600 {
+//!     for _ in 0..15 { cas.step().unwrap(); }
+//!     cas.tick();
+//! }
+//! println!("{}", cas.display);
+//! ```
+
+pub mod instruction;
+pub mod cartridge;
+pub mod font;
+pub mod quirks;
+pub mod keymap;
+pub mod state;
+pub mod snapshot;
+pub mod random;
+pub mod rewind;
+pub mod movie;
+pub mod image;
+pub mod debugger;
+pub mod disasm;
+pub mod asm;
+pub mod octo;
+pub mod trace;
+pub mod gdb;
+pub mod audio;
+
+#[cfg(feature = "gui")]
+pub mod window;
+
+pub use instruction::{encoding_of, from_opcode, mnemonic, Arg, DecodeError, Encoding, Instruction, Operation, Varset, ENCODINGS};
+pub use asm::{assemble, assemble_file, encode, AsmError, Assembly};
+pub use cartridge::{Access, Cartridge, MemoryError, WatchHit, Watchpoint};
+pub use debugger::{Breakpoint, DebugCommand, Debugger};
+pub use disasm::{disassemble, Disassembly};
+pub use font::Font;
+pub use gdb::GdbStub;
+pub use keymap::{Action, Keymap, KeymapConfig};
+pub use quirks::{IndexIncrement, MemoryOverflow, Quirks};
+pub use movie::{KeyScript, Movie};
+pub use random::{RandomSource, VipRandom, Xorshift};
+pub use rewind::Rewind;
+pub use snapshot::SnapshotError;
+pub use trace::{Divergence, TraceFormat, TraceRecord};
+pub use state::{Chip8State, ExecError, FaultReason, HexKeyboard, PixelEvent, StepOutcome, TermDisplay};
@@ -1,101 +1,636 @@
 use std::env;
-use std::io::Write;
-use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::fs::{self, File};
+use std::path::Path;
+#[cfg(feature = "gui")]
 use std::time::{Duration,Instant};
 
-mod instruction;
-use instruction::from_opcode;
+use chip8_emu::{assemble_file, disassemble, from_opcode, image, movie, octo, random, trace, Cartridge, Chip8State, Divergence, GdbStub, KeyScript, Movie, StepOutcome};
+use chip8_emu::{TraceFormat, TraceRecord, Watchpoint};
+use chip8_emu::audio::{Beeper, WavWriter};
+#[cfg(feature = "gui")]
+use chip8_emu::{debugger, Action, DebugCommand, Debugger, PixelEvent, Rewind, TermDisplay};
+#[cfg(feature = "gui")]
+use chip8_emu::window::Chip8Window;
 
-mod cartridge;
-use cartridge::Cartridge;
+mod cli;
+use cli::{Command, Options, Parsed};
 
-mod state;
-use state::{Chip8State, TermDisplay};
+fn main() {
+  let mut opts = match cli::parse(env::args().skip(1)) {
+    Ok(Parsed::Options(opts)) => opts,
+    Ok(Parsed::Help) => {
+      print!("{}", cli::USAGE);
+      return
+    },
+    Err(e) => {
+      eprint!("{}\n\n{}", e, cli::USAGE);
+      std::process::exit(2)
+    }
+  };
 
-mod window;
-use window::Chip8Window;
+  if opts.command == Command::TraceDiff {
+    std::process::exit(trace_diff(&opts))
+  }
+  if opts.command == Command::Asm {
+    std::process::exit(asm(&opts))
+  }
 
-fn main() {
-  let mode = "run";
+  // a replay runs with the settings it was recorded with
+  let replay = opts.replay.as_ref().map(|path| Movie::load(path).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(2)
+  }));
+  if let Some(movie) = &replay {
+    if !random::NAMES.contains(&movie.random.as_str()) {
+      eprintln!("{} was recorded with the random number generator {}, which is not one of {}", opts.replay.as_ref().unwrap(), movie.random, random::NAMES.join(", "));
+      std::process::exit(2)
+    }
+    opts.quirks = movie.quirks.clone();
+    opts.random = movie.random.clone();
+    opts.seed = Some(movie.seed);
+    opts.cycles_per_frame = movie.cycles_per_frame;
+    opts.font = movie.font.clone();
+    opts.font_address = movie.font_address;
+  }
+  if opts.record.is_some() {
+    opts.seed.get_or_insert_with(rand::random);
+  }
 
-  let filename = env::args().nth(1).expect("insert cartridge (.rom file)"); 
+  let mut cartridge = match Cartridge::new(opts.rom.clone(), opts.quirks.memory_size) {
+    Ok(cartridge) => cartridge,
+    Err(e) => {
+      eprintln!("cannot load {}: {}", opts.rom, e);
+      std::process::exit(2)
+    }
+  };
+  if let Err(e) = cartridge.load_font(&opts.font, opts.font_address) {
+    eprintln!("cannot load font: {}", e);
+    std::process::exit(2)
+  }
 
-  let cartridge = Cartridge::new(filename);
+  let mut outfile: Box<dyn Write> = match &opts.output {
+    Some(path) => match File::create(path) {
+      Ok(file) => Box::new(file),
+      Err(e) => {
+        eprintln!("cannot create {}: {}", path, e);
+        std::process::exit(2)
+      }
+    },
+    None => Box::new(io::stdout())
+  };
 
-  let mut outfile = OpenOptions::new().create(true).write(true).truncate(true).open(format!("{}.txt", mode)).unwrap();
+  // cli rejects combining these, and --record or --replay with --load-state
+  let tape = match (replay, &opts.record, &opts.input) {
+    (Some(movie), None, None) if movie.rom_hash != movie::rom_hash(&cartridge) => {
+      eprintln!("{} was recorded with another ROM", opts.replay.as_ref().unwrap());
+      std::process::exit(2)
+    },
+    (Some(movie), None, None) => Tape::Replay(movie),
+    (None, None, Some(path)) => Tape::Script(KeyScript::load(path).unwrap_or_else(|e| {
+      eprintln!("{}", e);
+      std::process::exit(2)
+    })),
+    (None, Some(path), None) => Tape::Record(Movie::new(movie::rom_hash(&cartridge), opts.quirks.clone(), &opts.random, opts.seed.unwrap(), opts.cycles_per_frame,
+      opts.font.clone(), opts.font_address), path.clone()),
+    (None, None, None) => Tape::Off,
+    _ => unreachable!("only one of --record, --replay and --input gets past cli::parse")
+  };
 
-  writeln!(outfile, "-----| {} |------", mode.to_ascii_uppercase()).unwrap();
-  match mode {
-    "listing" => {
-      for addr in (cartridge.start()..cartridge.len()).step_by(2) {
-        let opcode = cartridge.get_opcode_from(addr).unwrap();
-        writeln!(outfile, "{:#06x}  {:#06x}  {}", addr, opcode, from_opcode(opcode)).unwrap();
+  let state = match &opts.load_state {
+    Some(path) => match fs::read(path) {
+      Ok(state) => Some(state),
+      Err(e) => {
+        eprintln!("cannot read {}: {}", path, e);
+        std::process::exit(2)
       }
     },
-    "idle-run" => {
-      let mut cas = Chip8State::new(cartridge);
-      for cycle in 0..5000 {
-        let opcode = cas.cartridge.get_opcode_from(cas.pc).unwrap();
-        let instr = from_opcode( opcode );
-
-        if opcode & 0xf000 == 0xd000 {
-          write!(outfile, "{}", cas.display).unwrap();
-        } else {
-          writeln!(outfile, "{:4} opcode:{:#06x} {} {}", cycle, opcode, cas, instr).unwrap();
-        }
+    None => None
+  };
 
-        cas.run_instruction(instr);
-        if cycle % 4 == 0 {
-          cas.tick()
-        }
+  match opts.command {
+    Command::Disasm => disasm(&cartridge, &mut outfile),
+    Command::Trace | Command::Headless => run_headless(start(cartridge, &opts, state.as_deref()), tape, &opts, &mut outfile),
+    Command::TraceDiff | Command::Asm => unreachable!("trace-diff and asm do not load a ROM"),
+    Command::Gdb => gdb(start(cartridge, &opts, state.as_deref()), &opts),
+    Command::Run => run(start(cartridge.clone(), &opts, state.as_deref()), tape, cartridge, &opts)
+  }.unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(1)
+  })
+}
+
+/// an I/O error together with what was being done, for the message `main` prints before it gives up
+trait Context<T> {
+  fn context(self, doing: &str) -> Result<T, String>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+  fn context(self, doing: &str) -> Result<T, String> {
+    self.map_err(|e| format!("{}: {}", doing, e))
+  }
+}
+
+/// where the keypad input comes from besides the window: `--record` keeps it, `--replay` and `--input` play it back
+enum Tape {
+  Off,
+  Record(Movie, String),
+  Replay(Movie),
+  Script(KeyScript)
+}
+
+impl Tape {
+  #[cfg(feature = "gui")]
+  fn is_on(&self) -> bool {
+    !matches!(self, Tape::Off)
+  }
+
+  /// the keys for `frame`, given the keys held in the window
+  fn keys(&mut self, frame: u32, held: u16) -> u16 {
+    match self {
+      Tape::Off => held,
+      Tape::Record(movie, _) => {
+        movie.record(frame, held);
+        held
+      },
+      Tape::Replay(movie) => movie.keys_at(frame),
+      Tape::Script(script) => script.keys_at(frame)
+    }
+  }
+
+  /// no more input after the last recorded frame
+  fn is_over(&self, frame: u32) -> bool {
+    match self {
+      Tape::Replay(movie) => frame >= movie.length,
+      _ => false
+    }
+  }
+
+  /// takes or compares a state hash when `frames` have been completed
+  fn checkpoint(&mut self, frames: u32, cas: &Chip8State) {
+    if !Movie::is_checkpoint(frames) { return }
+    match self {
+      Tape::Off | Tape::Script(_) => {},
+      Tape::Record(movie, _) => movie.checkpoint(frames, movie::state_hash(cas)),
+      Tape::Replay(movie) => match movie.checkpoint_at(frames) {
+        Some(hash) if hash != movie::state_hash(cas) => eprintln!("replay desynced: the state after frame {} differs from the recording", frames),
+        _ => {}
+      }
+    }
+  }
+
+  /// saves a recording, `frame` is the frame the run stopped in
+  fn finish(self, frame: u32) -> Result<(), String> {
+    match self {
+      Tape::Record(mut movie, path) => {
+        movie.length = frame + 1;
+        movie.save(&path).context(&format!("cannot save the movie {}", path))
+      },
+      _ => Ok(())
+    }
+  }
+}
+
+/// the program as assembly source that assembles into the same ROM again
+fn disasm(cartridge: &Cartridge, outfile: &mut dyn Write) -> Result<(), String> {
+  let rom = &cartridge.memory[cartridge.start() as usize..cartridge.len()];
+  write!(outfile, "{}", disassemble(rom, cartridge.start())).context("cannot write output")
+}
+
+/// assembles (or compiles, for Octo `.8o` files) the source given as ROM into a ROM image and a symbol map next to it
+fn asm(opts: &Options) -> i32 {
+  let compiled = if opts.rom.ends_with(".8o") { octo::compile_file(&opts.rom) } else { assemble_file(&opts.rom) };
+  let assembly = match compiled {
+    Ok(assembly) => assembly,
+    Err(e) => {
+      eprintln!("{}", e);
+      return 2
+    }
+  };
+  let rom = opts.output.clone().unwrap_or_else(|| Path::new(&opts.rom).with_extension("ch8").display().to_string());
+  let symbols = Path::new(&rom).with_extension("sym");
+  let written = fs::write(&rom, &assembly.rom).map_err(|e| format!("cannot write {}: {}", rom, e))
+    .and_then(|_| fs::write(&symbols, assembly.symbol_map()).map_err(|e| format!("cannot write {}: {}", symbols.display(), e)));
+  match written {
+    Ok(()) => {
+      println!("{}: {} bytes, {} symbols in {}", rom, assembly.rom.len(), assembly.symbols.len(), symbols.display());
+      0
+    },
+    Err(e) => {
+      eprintln!("{}", e);
+      2
+    }
+  }
+}
+
+/// compares the traces given as ROM and second file, the exit status is 1 if they differ
+fn trace_diff(opts: &Options) -> i32 {
+  let read = |path: &str| fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e));
+  let traces = read(&opts.rom).and_then(|first| Ok((first, read(&opts.against)?)));
+  match traces.and_then(|(first, second)| trace::diff(&first, &second)) {
+    Ok(None) => {
+      println!("the traces are the same");
+      0
+    },
+    Ok(Some(Divergence::Fields { record, differences })) => {
+      println!("the traces diverge at record {}:", record);
+      for (name, first, second) in differences {
+        println!("  {}: {} in {}, {} in {}", name, first, opts.rom, second, opts.against)
       }
+      1
     },
-    _ => {
-      let frame_duration = Duration::from_micros(16_666);
-      let cpu_cycles_per_frame = 29_333u16; // ~1.76 MHz, cosmac vp
+    Ok(Some(Divergence::Length { record, first_is_longer })) => {
+      let (longer, shorter) = if first_is_longer { (&opts.rom, &opts.against) } else { (&opts.against, &opts.rom) };
+      println!("{} ends after {} records, {} goes on", shorter, record, longer);
+      1
+    },
+    Err(e) => {
+      eprintln!("{}", e);
+      2
+    }
+  }
+}
 
-      let mut cas = Chip8State::new(cartridge);
+/// the machine at the beginning of the ROM, with the random number generator from the command line
+fn machine(cartridge: Cartridge, opts: &Options) -> Chip8State {
+  let mut cas = Chip8State::new(cartridge, opts.quirks.clone());
+  let seed = opts.seed.unwrap_or_else(rand::random);
+  cas.set_random(random::from_name(&opts.random, seed).expect("generator name was checked by the parser"));
+  cas
+}
 
-      let mut cwin = Chip8Window::new(TermDisplay::WIDTH_PX as usize, TermDisplay::HEIGHT_PX as usize);
-      let mut monitor_now = Instant::now();
-      let mut monitor_remaining = frame_duration;
+/// the machine at the beginning of the ROM, or in the save state given on the command line
+fn start(cartridge: Cartridge, opts: &Options, state: Option<&[u8]>) -> Chip8State {
+  let mut cas = machine(cartridge, opts);
+  for watchpoint in &opts.watchpoints {
+    cas.cartridge.add_watchpoint(watchpoint.clone())
+  }
+  if let Some(state) = state {
+    if let Err(e) = cas.load_state(state) {
+      eprintln!("cannot load state: {}", e);
+      std::process::exit(2)
+    }
+  }
+  cas
+}
+
+/// runs without a window; `trace` prints every instruction, `headless` only the final display
+fn run_headless(mut cas: Chip8State, mut tape: Tape, opts: &Options, outfile: &mut dyn Write) -> Result<(), String> {
+  let tracing = opts.command == Command::Trace;
+  // a replay goes on until its input ends, and --frames until enough frames are done
+  let unlimited = matches!(tape, Tape::Replay(_)) || opts.frames.is_some();
+  let cycle_limit = opts.cycle_limit.unwrap_or(if unlimited { u64::MAX } else { 5000 });
 
-      while cwin.is_active() {
+  let mut beeper = Beeper::new(opts.volume, opts.tone, Beeper::SAMPLE_RATE);
+  let mut wav = match &opts.wav {
+    Some(path) => Some(WavWriter::create(path, beeper.sample_rate()).context(&format!("cannot create {}", path))?),
+    None => None
+  };
 
-        for _ in 0..cpu_cycles_per_frame {
-          loop {
-            match cas.display.flips.pop_front() {
-              Some(p) => cwin.draw_pixel(&p),
-              None => break
+  // structured traces record the memory writes through a watchpoint of their own
+  let structured = tracing && opts.trace_format != TraceFormat::Text;
+  let all_writes = cas.cartridge.watchpoints().len();
+  if structured {
+    cas.cartridge.add_watchpoint(Watchpoint { start: 0, end: u16::MAX, read: false, write: true, execute: false, pause: false });
+    if opts.trace_format == TraceFormat::Csv {
+      writeln!(outfile, "{}", TraceRecord::CSV_HEADER).context("cannot write output")?
+    }
+  }
+
+  let mut cycle = 0;
+  let mut frame_cycles = 0;
+  let mut frame = 0;
+  tape.checkpoint(frame, &cas);
+  while cycle < cycle_limit && !tape.is_over(frame) && opts.frames.map(|n| frame < n).unwrap_or(true) {
+    if frame_cycles == 0 {
+      cas.keyboard.set_state(tape.keys(frame, 0)) // the keys of a frame are held from its first instruction on
+    }
+    let (pc, opcode) = (cas.pc, cas.cartridge.get_opcode_from(cas.pc).unwrap_or(0));
+    if tracing && !structured {
+      if opcode & 0xf000 == 0xd000 {
+        write!(outfile, "{}", cas.display).context("cannot write output")?;
+      } else if let Ok(instr) = from_opcode( opcode ) {
+        writeln!(outfile, "{:4} opcode:{:#06x} {} {}", cycle, opcode, cas, instr).context("cannot write output")?;
+      }
+    }
+
+    let outcome = cas.step();
+    let mut writes = Vec::new();
+    for hit in cas.take_watch_hits() {
+      if structured && hit.watchpoint == all_writes {
+        writes.push((hit.address, hit.new))
+      } else if structured {
+        eprintln!("{}", hit)
+      } else {
+        writeln!(outfile, "{}", hit).context("cannot write output")?
+      }
+    }
+    let outcome = match outcome {
+      Ok(outcome) => outcome,
+      Err(e) if structured => {
+        eprintln!("{}\n{}", e, cas.register_dump());
+        break
+      },
+      Err(e) => {
+        writeln!(outfile, "{}\n{}", e, cas.register_dump()).context("cannot write output")?;
+        break
+      }
+    };
+    if structured {
+      let record = TraceRecord::new(cycle, frame, pc, opcode, &cas, writes);
+      writeln!(outfile, "{}", if opts.trace_format == TraceFormat::Csv { record.to_csv() } else { record.to_json() }).context("cannot write output")?
+    }
+    cycle += 1;
+    frame_cycles += 1;
+    if outcome == StepOutcome::Exit { break }
+
+    if outcome == StepOutcome::WaitingForVblank || frame_cycles >= opts.cycles_per_frame {
+      frame_cycles = 0;
+      // the frame sounds while the timer is set, before this tick counts it down
+      if let Some(wav) = &mut wav {
+        wav.write(&beeper.render_frame(cas.is_sounding(), cas.audio_pattern.as_ref().map(|p| (p, cas.pitch))))
+          .context(&format!("cannot write {}", opts.wav.as_ref().unwrap()))?
+      }
+      cas.tick();
+      frame += 1;
+      tape.checkpoint(frame, &cas);
+    }
+  }
+
+  if opts.hash {
+    writeln!(outfile, "{:016x}", image::framebuffer_hash(&cas.display)).context("cannot write output")?
+  } else if !tracing && opts.screenshot.is_none() {
+    write!(outfile, "{}", cas.display).context("cannot write output")?;
+  }
+  if let Some(path) = &opts.screenshot {
+    fs::write(path, if path.ends_with(".pbm") { image::pbm(&cas.display) } else { image::png(&cas.display, &opts.palette) })
+      .context(&format!("cannot write the screenshot {}", path))?
+  }
+  if let Some(path) = &opts.save_state {
+    fs::write(path, cas.save_state()).context(&format!("cannot save the state to {}", path))?
+  }
+  tape.finish(frame)?;
+  match wav {
+    Some(wav) => wav.finish().context(&format!("cannot write {}", opts.wav.as_ref().unwrap())),
+    None => Ok(())
+  }
+}
+
+/// serves gdb connections one after the other until one of them kills the machine
+fn gdb(mut cas: Chip8State, opts: &Options) -> Result<(), String> {
+  let listener = std::net::TcpListener::bind(("127.0.0.1", opts.port)).context(&format!("cannot listen on 127.0.0.1:{}", opts.port))?;
+  let mut stub = GdbStub::new(opts.cycles_per_frame);
+  eprintln!("waiting for gdb on 127.0.0.1:{}, connect with: target remote :{}", opts.port, opts.port);
+  for stream in listener.incoming() {
+    let mut stream = stream.context("cannot accept the gdb connection")?;
+    eprintln!("gdb connected from {}", stream.peer_addr().context("cannot accept the gdb connection")?);
+    match stub.serve(&mut cas, &mut stream) {
+      Ok(faults) => faults.iter().for_each(|e| eprintln!("{}\n{}", e, cas.register_dump())),
+      Err(e) => eprintln!("gdb connection lost: {}", e)
+    }
+    if stub.killed() { break }
+    eprintln!("gdb detached, waiting for the next connection");
+  }
+  Ok(())
+}
+
+/// where the window keeps save state `slot` of the ROM
+#[cfg(feature = "gui")]
+fn slot_path(opts: &Options, slot: u8) -> String {
+  format!("{}.state{}", opts.rom, slot)
+}
+
+/// lines typed into the terminal, read on their own thread so that the window keeps running
+#[cfg(feature = "gui")]
+fn debugger_prompt() -> std::sync::mpsc::Receiver<String> {
+  let (tx, rx) = std::sync::mpsc::channel();
+  std::thread::spawn(move || {
+    let stdin = io::stdin();
+    let mut line = String::new();
+    while stdin.read_line(&mut line).map(|n| n > 0).unwrap_or(false) {
+      if tx.send(line.trim().to_string()).is_err() { break }
+      line.clear()
+    }
+  });
+  rx
+}
+
+/// prints the watched memory accesses of the last instruction, true if one of them pauses the machine
+#[cfg(feature = "gui")]
+fn report_watch_hits(cas: &mut Chip8State) -> bool {
+  let hits = cas.take_watch_hits();
+  hits.iter().for_each(|hit| println!("{}", hit));
+  hits.iter().any(|hit| cas.cartridge.watchpoints().get(hit.watchpoint).map(|w| w.pause).unwrap_or(false))
+}
+
+/// `cartridge` is the ROM as loaded, for restarting it
+#[cfg(feature = "gui")]
+fn run(mut cas: Chip8State, mut tape: Tape, cartridge: Cartridge, opts: &Options) -> Result<(), String> {
+  let frame_duration = Duration::from_micros(16_666);
+
+  #[cfg(feature = "audio")]
+  let mut beeper = Beeper::new(opts.volume, opts.tone, Beeper::SAMPLE_RATE);
+  #[cfg(feature = "audio")]
+  let audio_out = chip8_emu::audio::AudioOutput::new(beeper.sample_rate()).map_err(|e| eprintln!("no sound: {}", e)).ok();
+
+  let mut cwin = Chip8Window::new(TermDisplay::WIDTH_PX as usize, TermDisplay::HEIGHT_PX as usize, opts.scale, opts.palette);
+  let mut monitor_now = Instant::now();
+  let mut monitor_remaining = frame_duration;
+  let mut cycle = 0u64;
+  let mut paused = false;
+  let mut rewind = Rewind::new(60 * opts.rewind_seconds as usize);
+  let mut frame = 0u32;
+  tape.checkpoint(frame, &cas);
+  let prompt = if opts.debug {
+    println!("debugger: the emulation is paused, type help for the commands");
+    paused = true;
+    Some(debugger_prompt())
+  } else {
+    None
+  };
+  let mut debugger = Debugger::new();
+  let mut frames_left: Option<u32> = None; // paused again after these frames
+
+  'running: while cwin.is_active() && !tape.is_over(frame) {
+    // a movie only holds input, jumping around in time would make it useless
+    let rewinding = !tape.is_on() && cwin.held_actions(&opts.keymap).contains(&Action::Rewind);
+    if rewinding {
+      if let Some(state) = rewind.pop() {
+        cas.load_state(&state).expect("rewind holds valid states")
+      }
+    }
+    let mut steps = if paused || rewinding { 0 } else { opts.cycles_per_frame };
+    if steps > 0 {
+      cas.keyboard.set_state(tape.keys(frame, cwin.keypad_state(&opts.keymap))) // held from the frame's first instruction on
+    }
+    for line in prompt.iter().flat_map(|p| p.try_iter()) {
+      match debugger::parse_command(&line) {
+        Ok(DebugCommand::Continue) => {
+          debugger.resume(&cas);
+          paused = false
+        },
+        Ok(DebugCommand::Pause) => {
+          paused = true;
+          println!("{}", debugger::describe(&cas))
+        },
+        Ok(DebugCommand::Step(_)) if !paused => println!("pause first"),
+        Ok(DebugCommand::Step(_)) if tape.is_on() => println!("stepping is not available while recording or replaying"),
+        Ok(DebugCommand::Step(n)) => {
+          debugger.resume(&cas); // the machine is paused at the first instruction, breakpoint or not
+          for _ in 0..n {
+            if let Some(b) = debugger.hit(&cas) {
+              println!("breakpoint {} at {}", b, debugger.breakpoints[b]);
+              break
             }
+            let outcome = cas.step();
+            let watched = report_watch_hits(&mut cas);
+            match outcome {
+              Ok(StepOutcome::WaitingForVblank) => { println!("waiting for the next frame"); break },
+              Ok(StepOutcome::Exit) => break 'running,
+              Ok(_) => {},
+              Err(e) => { println!("{}", e); break }
+            }
+            if watched { break }
           }
+          println!("{}", debugger::describe(&cas))
+        },
+        Ok(DebugCommand::Frame(n)) => {
+          debugger.resume(&cas);
+          frames_left = Some(n);
+          paused = false
+        },
+        Ok(DebugCommand::Break(b)) => {
+          println!("breakpoint {} at {}", debugger.breakpoints.len(), b);
+          debugger.breakpoints.push(b)
+        },
+        Ok(DebugCommand::Delete(n)) if n < debugger.breakpoints.len() => { debugger.breakpoints.remove(n); },
+        Ok(DebugCommand::Delete(n)) => println!("there is no breakpoint {}", n),
+        Ok(DebugCommand::Watch(w)) => {
+          println!("watchpoint {} on {}", cas.cartridge.watchpoints().len(), w);
+          cas.cartridge.add_watchpoint(w)
+        },
+        Ok(DebugCommand::Unwatch(n)) => if cas.cartridge.remove_watchpoint(n).is_none() { println!("there is no watchpoint {}", n) },
+        Ok(DebugCommand::List) => {
+          debugger.breakpoints.iter().enumerate().for_each(|(n, b)| println!("breakpoint {}: {}", n, b));
+          cas.cartridge.watchpoints().iter().enumerate().for_each(|(n, w)| println!("watchpoint {}: {}", n, w))
+        },
+        Ok(DebugCommand::Registers) => println!("{}", debugger::describe(&cas)),
+        Ok(DebugCommand::Help) => print!("{}", debugger::HELP),
+        Ok(DebugCommand::Quit) => break 'running,
+        Err(e) if e.is_empty() => {},
+        Err(e) => println!("{}", e)
+      }
+    }
+    if paused { steps = 0 }
 
-          let opcode = cas.cartridge.get_opcode_from(cas.pc).unwrap();
-          let instruction = from_opcode( opcode );
-          cas.run_instruction(instruction);
-        }
+    for action in cwin.actions(&opts.keymap) {
+      match action {
+        Action::Step | Action::Reset | Action::LoadState(_) if tape.is_on() => eprintln!("{:?} is not available while recording or replaying", action),
+        Action::Pause => {
+          debugger.resume(&cas);
+          paused = !paused
+        },
+        Action::Step if paused => {
+          debugger.resume(&cas); // off the breakpoint it stopped at
+          steps = 1
+        },
+        Action::Step | Action::Rewind => {},
+        Action::Reset => {
+          let watchpoints = cas.cartridge.watchpoints().to_vec();
+          cas = machine(cartridge.clone(), opts);
+          watchpoints.into_iter().for_each(|w| cas.cartridge.add_watchpoint(w));
+          cwin.draw_pixel(&PixelEvent::Resize { width: TermDisplay::WIDTH_PX, height: TermDisplay::HEIGHT_PX });
+          rewind.clear() // the frames before the restart belong to another run
+        },
+        Action::SaveState(slot) => match fs::write(slot_path(opts, slot), cas.save_state()) {
+          Ok(()) => eprintln!("saved state {}", slot),
+          Err(e) => eprintln!("cannot save state {}: {}", slot, e)
+        },
+        Action::LoadState(slot) => match fs::read(slot_path(opts, slot)).map_err(|e| e.to_string())
+          .and_then(|state| cas.load_state(&state).map_err(|e| e.to_string())) {
+          Ok(()) => {
+            rewind.clear();
+            eprintln!("loaded state {}", slot)
+          },
+          Err(e) => eprintln!("cannot load state {}: {}", slot, e)
+        },
+        Action::Quit => break 'running
+      }
+    }
 
-        loop {
-          let monitor_elapsed = monitor_now.elapsed();
-          if monitor_elapsed < monitor_remaining {
-            std::thread::sleep(monitor_remaining - monitor_elapsed)
-          } else {
-            let tau = monitor_elapsed - monitor_remaining;
-            monitor_remaining = if frame_duration > tau { frame_duration - tau } else { Duration::from_millis(0) };
-            monitor_now = Instant::now();
-            break
-          }
+    for _ in 0..steps {
+      while let Some(p) = cas.display.flips.pop_front() {
+        cwin.draw_pixel(&p)
+      }
+
+      if opts.cycle_limit.map(|limit| cycle >= limit).unwrap_or(false) { break 'running }
+      if let Some(n) = debugger.hit(&cas) {
+        println!("breakpoint {} at {}\n{}", n, debugger.breakpoints[n], debugger::describe(&cas));
+        paused = true;
+        frames_left = None;
+        break
+      }
+      cycle += 1;
+
+      let outcome = cas.step();
+      if report_watch_hits(&mut cas) {
+        println!("{}", debugger::describe(&cas));
+        paused = true;
+        frames_left = None;
+        if outcome.is_ok() { break }
+      }
+      match outcome {
+        Ok(StepOutcome::WaitingForVblank) => break,
+        Ok(StepOutcome::Exit) => break 'running,
+        Ok(_) => {},
+        Err(e) => {
+          eprintln!("{}\n{}", e, cas.register_dump());
+          break 'running
         }
+      }
+    }
 
-        match cwin.check_keypress() {
-          Some(k) => cas.keyboard.push(k),
-          None => {}
+    loop {
+      let monitor_elapsed = monitor_now.elapsed();
+      if monitor_elapsed < monitor_remaining {
+        std::thread::sleep(monitor_remaining - monitor_elapsed)
+      } else {
+        let tau = monitor_elapsed - monitor_remaining;
+        monitor_remaining = if frame_duration > tau { frame_duration - tau } else { Duration::from_millis(0) };
+        monitor_now = Instant::now();
+        break
+      }
+    }
+
+    while let Some(p) = cas.display.flips.pop_front() {
+      cwin.draw_pixel(&p)
+    }
+
+    cwin.update_window();
+    // the frame sounds while the timer is set, before this tick counts it down
+    #[cfg(feature = "audio")]
+    if let Some(out) = &audio_out {
+      out.push(&beeper.render_frame(cas.is_sounding(), cas.audio_pattern.as_ref().map(|p| (p, cas.pitch))))
+    }
+    if !paused && !rewinding {
+      cas.tick();
+      frame += 1;
+      tape.checkpoint(frame, &cas);
+      rewind.push(cas.save_state());
+      if let Some(n) = frames_left {
+        frames_left = if n > 1 { Some(n - 1) } else {
+          paused = true;
+          println!("{}", debugger::describe(&cas));
+          None
         }
-        
-        cwin.update_window();
-        cas.tick();
       }
     }
+
   }
+  tape.finish(frame)
+}
 
+#[cfg(not(feature = "gui"))]
+fn run(_cas: Chip8State, _tape: Tape, _cartridge: Cartridge, _opts: &Options) -> Result<(), String> {
+  Err("chip8-emu was built without the gui feature, use the headless or trace commands instead".to_string())
 }
@@ -0,0 +1,245 @@
+use std::convert::TryInto;
+use std::fs;
+
+use crate::cartridge::Cartridge;
+use crate::font::Font;
+use crate::quirks::{IndexIncrement, MemoryOverflow, Quirks};
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+use crate::state::Chip8State;
+
+const MAGIC: &[u8; 4] = b"C8MV";
+const VERSION: u8 = 3;
+
+/// recorded keypad input of a run, enough to replay it exactly
+///
+/// The header holds everything else a run depends on: the ROM, the quirks, the random number
+/// generator and its seed, the number of instructions per frame, and the font and its address. The body holds the keypad
+/// state (bit k is key k) from each frame on in which it changed, and hashes of the whole machine
+/// state every `CHECKPOINT_INTERVAL` frames to notice when a replay goes its own way.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub quirks: Quirks,
+    pub random: String,
+    pub seed: u64,
+    pub cycles_per_frame: u32,
+    pub font: Font,
+    pub font_address: u16,
+    pub length: u32, // frames, the last one may have been cut short
+    changes: Vec<(u32, u16)>,
+    checkpoints: Vec<(u32, u64)>
+}
+
+impl Movie {
+    pub const CHECKPOINT_INTERVAL: u32 = 60;
+
+    pub fn new(rom_hash: u64, quirks: Quirks, random: &str, seed: u64, cycles_per_frame: u32, font: Font, font_address: u16) -> Self {
+        Self { rom_hash, quirks, random: random.to_string(), seed, cycles_per_frame, font, font_address,
+            length: 0, changes: Vec::new(), checkpoints: Vec::new() }
+    }
+
+    /// notes the keys held during `frame`, only changes are stored
+    pub fn record(&mut self, frame: u32, keys: u16) {
+        self.length = self.length.max(frame + 1);
+        match self.changes.last_mut() {
+            Some((last, held)) if *last == frame => *held = keys,
+            Some((_, held)) if *held == keys => {},
+            None if keys == 0 => {},
+            _ => self.changes.push((frame, keys))
+        }
+    }
+
+    /// the keys held during `frame`
+    pub fn keys_at(&self, frame: u32) -> u16 {
+        keys_at(&self.changes, frame)
+    }
+
+    pub fn is_checkpoint(frame: u32) -> bool {
+        frame.is_multiple_of(Self::CHECKPOINT_INTERVAL)
+    }
+
+    pub fn checkpoint(&mut self, frame: u32, hash: u64) {
+        self.checkpoints.push((frame, hash))
+    }
+
+    /// the state hash recorded after `frame`, if it is a checkpoint
+    pub fn checkpoint_at(&self, frame: u32) -> Option<u64> {
+        self.checkpoints.iter().find(|(f, _)| *f == frame).map(|(_, hash)| *hash)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::with_header(MAGIC, VERSION);
+        w.u64(self.rom_hash);
+        write_quirks(&mut w, &self.quirks);
+        w.bytes(self.random.as_bytes());
+        w.u64(self.seed);
+        w.u32(self.cycles_per_frame);
+        w.bytes(&self.font.small);
+        w.bytes(&self.font.big);
+        w.u16(self.font_address);
+        w.u32(self.length);
+        w.u32(self.changes.len() as u32);
+        for (frame, keys) in &self.changes {
+            w.u32(*frame);
+            w.u16(*keys)
+        }
+        w.u32(self.checkpoints.len() as u32);
+        for (frame, hash) in &self.checkpoints {
+            w.u32(*frame);
+            w.u64(*hash)
+        }
+        w.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        Self::read(bytes).map_err(|e| match e {
+            SnapshotError::NotASnapshot => "not a chip8-emu movie".to_string(),
+            SnapshotError::UnsupportedVersion(v) => format!("movie version {} is not supported (expected {})", v, VERSION),
+            SnapshotError::Truncated => "movie is truncated".to_string(),
+            SnapshotError::Invalid(what) => format!("movie is invalid: {}", what)
+        })
+    }
+
+    fn read(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut r = StateReader::with_header(bytes, MAGIC, VERSION)?;
+        let rom_hash = r.u64()?;
+        let quirks = read_quirks(&mut r)?;
+        let random = String::from_utf8(r.bytes()?.to_vec()).map_err(|_| SnapshotError::Invalid("random number generator"))?;
+        let (seed, cycles_per_frame) = (r.u64()?, r.u32()?);
+        let font = Font {
+            small: r.bytes()?.try_into().map_err(|_| SnapshotError::Invalid("font"))?,
+            big: r.bytes()?.try_into().map_err(|_| SnapshotError::Invalid("font"))?
+        };
+        let mut movie = Self::new(rom_hash, quirks, &random, seed, cycles_per_frame, font, r.u16()?);
+        movie.length = r.u32()?;
+        for _ in 0..r.u32()? {
+            movie.changes.push((r.u32()?, r.u16()?))
+        }
+        for _ in 0..r.u32()? {
+            movie.checkpoints.push((r.u32()?, r.u64()?))
+        }
+        r.finish()?;
+        if movie.changes.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err(SnapshotError::Invalid("key changes out of order"))
+        }
+        Ok(movie)
+    }
+
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let bytes = fs::read(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+        Self::from_bytes(&bytes).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        fs::write(filename, self.to_bytes())
+    }
+}
+
+/// hand-written keypad input for headless runs, one `FRAME KEYS...` line per change:
+///
+/// ```text
+/// # keys are hex digits, - releases all of them
+/// 30 5      hold 5 from frame 30 on
+/// 32 5 6
+/// 40 -
+/// ```
+#[derive(Debug,Clone,PartialEq)]
+pub struct KeyScript {
+    changes: Vec<(u32, u16)>
+}
+
+impl KeyScript {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut changes: Vec<(u32, u16)> = Vec::new();
+        for (n, raw) in text.lines().enumerate() {
+            let mut words = raw.split('#').next().unwrap_or("").split_whitespace();
+            let frame = match words.next() {
+                Some(frame) => frame.parse().map_err(|_| format!("line {}: {} is not a frame number", n + 1, frame))?,
+                None => continue
+            };
+            if changes.last().map(|(last, _)| *last >= frame).unwrap_or(false) {
+                return Err(format!("line {}: frame {} does not come after the previous line", n + 1, frame))
+            }
+            let mut keys = 0;
+            for word in words.filter(|w| *w != "-") {
+                match u8::from_str_radix(word, 16) {
+                    Ok(k) if k < 16 => keys |= 1 << k,
+                    _ => return Err(format!("line {}: {} is not a hex key 0-F", n + 1, word))
+                }
+            }
+            changes.push((frame, keys))
+        }
+        Ok(Self { changes })
+    }
+
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(filename).map_err(|e| format!("cannot read {}: {}", filename, e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", filename, e))
+    }
+
+    /// the keys held during `frame`
+    pub fn keys_at(&self, frame: u32) -> u16 {
+        keys_at(&self.changes, frame)
+    }
+}
+
+/// the keys of the last change at or before `frame` in `changes`, which are ordered by frame
+fn keys_at(changes: &[(u32, u16)], frame: u32) -> u16 {
+    match changes.binary_search_by_key(&frame, |(f, _)| *f) {
+        Ok(n) => changes[n].1,
+        Err(0) => 0,
+        Err(n) => changes[n - 1].1
+    }
+}
+
+/// 64-bit FNV-1a
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// hash of the program loaded into `cartridge`
+pub fn rom_hash(cartridge: &Cartridge) -> u64 {
+    hash(&cartridge.memory[cartridge.start() as usize..cartridge.len()])
+}
+
+/// hash of the complete machine state, equal states have equal save states
+pub fn state_hash(cas: &Chip8State) -> u64 {
+    hash(&cas.save_state())
+}
+
+fn write_quirks(w: &mut StateWriter, quirks: &Quirks) {
+    w.bool(quirks.shift_uses_vy);
+    w.u8(match quirks.load_store_index {
+        IndexIncrement::Unchanged => 0,
+        IndexIncrement::ByX => 1,
+        IndexIncrement::ByXPlusOne => 2
+    });
+    w.bool(quirks.jump_uses_vx);
+    w.bool(quirks.logic_resets_vf);
+    w.bool(quirks.clip_sprites);
+    w.bool(quirks.display_wait);
+    w.bool(quirks.lores_dxy0_8x16);
+    w.u32(quirks.memory_size as u32);
+    w.bool(quirks.memory_overflow == MemoryOverflow::Fault)
+}
+
+fn read_quirks(r: &mut StateReader) -> Result<Quirks, SnapshotError> {
+    let shift_uses_vy = r.bool()?;
+    let load_store_index = match r.u8()? {
+        0 => IndexIncrement::Unchanged,
+        1 => IndexIncrement::ByX,
+        2 => IndexIncrement::ByXPlusOne,
+        _ => return Err(SnapshotError::Invalid("load/store quirk"))
+    };
+    let jump_uses_vx = r.bool()?;
+    let logic_resets_vf = r.bool()?;
+    let clip_sprites = r.bool()?;
+    let display_wait = r.bool()?;
+    let lores_dxy0_8x16 = r.bool()?;
+    let memory_size = r.u32()? as usize;
+    if memory_size != Cartridge::MEMORY_SIZE && memory_size != Cartridge::XO_CHIP_MEMORY_SIZE {
+        return Err(SnapshotError::Invalid("memory size"))
+    }
+    let memory_overflow = if r.bool()? { MemoryOverflow::Fault } else { MemoryOverflow::Wrap };
+    Ok(Quirks { shift_uses_vy, load_store_index, jump_uses_vx, logic_resets_vf, clip_sprites, display_wait, lores_dxy0_8x16, memory_size, memory_overflow })
+}
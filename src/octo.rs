@@ -0,0 +1,723 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::asm::{encode, AsmError, Assembly, Operand, Position};
+
+/// where Octo programs are loaded, like every other CHIP-8 program
+const ORIGIN: u32 = 0x200;
+
+/// words that cannot name labels, constants, aliases or macros
+const KEYWORDS: [&str; 40] = [":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=", "==", "!=", "<", ">", "<=", ">=",
+    "key", "-key", "hex", "bighex", "random", "delay", "buzzer", "pitch", "if", "then", "begin", "else", "end",
+    "loop", "again", "while", "jump", "jump0", "native", "return", "clear", "sprite", "long", "i", ";"];
+
+/// a word of the source, Octo separates everything by whitespace
+#[derive(Debug,Clone)]
+struct Token {
+    text: String,
+    at: Position
+}
+
+fn tokenize(source: &str, file: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (n, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i].is_whitespace() { i += 1; continue }
+            if chars[i] == '#' { break }
+            let start = i;
+            if chars[i] == '"' {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' { i += 1 }
+                i = (i + 1).min(chars.len());
+            } else {
+                while i < chars.len() && !chars[i].is_whitespace() { i += 1 }
+            }
+            tokens.push(Token { text: chars[start..i].iter().collect(), at: Position { file: file.to_string(), line: n + 1, column: start + 1 } });
+        }
+    }
+    tokens
+}
+
+fn error<T>(at: &Position, message: String) -> Result<T, AsmError> {
+    Err(AsmError { at: at.clone(), message })
+}
+
+/// decimal, 0x hex or 0b binary, optionally negative
+fn literal(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text)
+    };
+    let value = match digits.get(..2) {
+        Some("0x") | Some("0X") => i64::from_str_radix(&digits[2..], 16).ok(),
+        Some("0b") | Some("0B") => i64::from_str_radix(&digits[2..], 2).ok(),
+        _ if digits.starts_with(|c: char| c.is_ascii_digit()) => digits.parse().ok(),
+        _ => None
+    }?;
+    Some(if negative { -value } else { value })
+}
+
+fn is_register(text: &str) -> bool {
+    text.len() == 2 && (text.starts_with('v') || text.starts_with('V')) && u8::from_str_radix(&text[1..], 16).is_ok()
+}
+
+/// an instruction for `encode`, with the position of every operand
+type Instruction = (&'static str, Vec<(Operand, Position)>);
+
+/// how an address that is not known yet goes into the program once it is
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Fixup {
+    Addr,        // the low 12 bits of the opcode
+    Long,        // a 16-bit word
+    Unpack(bool) // the operands of `v0 := ...; v1 := ...`, `:unpack long` puts all 16 bits into them
+}
+
+struct Reference {
+    address: u32,
+    fixup: Fixup,
+    name: String,
+    at: Position
+}
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>
+}
+
+/// a control structure waiting for its end
+enum Block {
+    If { jump: u32, at: Position },                      // the jump over the block, from `begin` or `else`
+    Loop { start: u32, whiles: Vec<u32>, at: Position }  // and the jumps out of it
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    pos: usize,
+    end: Position,
+    rom: Vec<u8>,
+    here: u32,
+    labels: BTreeMap<String, (u32, Position)>,
+    constants: BTreeMap<String, f64>,
+    aliases: BTreeMap<String, u8>,
+    macros: BTreeMap<String, Macro>,
+    references: Vec<Reference>,
+    blocks: Vec<Block>,
+    expansions: usize
+}
+
+/// compiles the Octo source at `path`
+pub fn compile_file(path: &str) -> Result<Assembly, AsmError> {
+    let text = fs::read_to_string(path).map_err(|e| AsmError { at: Position { file: path.to_string(), line: 0, column: 0 }, message: e.to_string() })?;
+    compile(&text, path)
+}
+
+/// compiles Octo source, which calls itself `name` in errors, into a program for 0x200
+///
+/// The program starts at the label `main`: unless `: main` comes first, the ROM begins with
+/// a jump to it. Labels and constants end up in the symbols of the `Assembly`.
+pub fn compile(source: &str, name: &str) -> Result<Assembly, AsmError> {
+    let tokens = tokenize(source, name);
+    let end = tokens.last().map(|t| Position { column: t.at.column + t.text.chars().count(), ..t.at.clone() })
+        .unwrap_or(Position { file: name.to_string(), line: 1, column: 1 });
+    let mut c = Compiler { tokens, pos: 0, end, rom: Vec::new(), here: ORIGIN, labels: BTreeMap::new(), constants: BTreeMap::new(),
+        aliases: BTreeMap::new(), macros: BTreeMap::new(), references: Vec::new(), blocks: Vec::new(), expansions: 0 };
+    let start = Position { file: name.to_string(), line: 1, column: 1 };
+    let main_first = c.tokens.len() >= 2 && c.tokens[0].text == ":" && c.tokens[1].text == "main";
+    if !main_first {
+        c.references.push(Reference { address: ORIGIN, fixup: Fixup::Addr, name: "main".to_string(), at: start.clone() });
+        c.emit(("JP", vec![(Operand::Value(0), start.clone())]), &start)?;
+    }
+    while c.pos < c.tokens.len() {
+        c.statement()?;
+    }
+    c.finish()
+}
+
+impl Compiler {
+    fn next(&mut self, what: &str) -> Result<Token, AsmError> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                Ok(t.clone())
+            },
+            None => error(&self.end, format!("expected {} but the source ends", what))
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let t = self.next(text)?;
+        if t.text != text { return error(&t.at, format!("expected {} but found {}", text, t.text)) }
+        Ok(t)
+    }
+
+    fn byte(&mut self, value: u8, at: &Position) -> Result<(), AsmError> {
+        if self.here > 0xffff { return error(at, "the program does not fit into 64 KiB of memory".to_string()) }
+        let n = (self.here - ORIGIN) as usize;
+        if self.rom.len() <= n { self.rom.resize(n + 1, 0) }
+        self.rom[n] = value;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, (mnemonic, operands): Instruction, at: &Position) -> Result<(), AsmError> {
+        let values: Vec<Operand> = operands.iter().map(|(o, _)| o.clone()).collect();
+        match encode(mnemonic, &values) {
+            Ok(bytes) => bytes.iter().try_for_each(|b| self.byte(*b, at)),
+            Err(e) => error(e.operand.map(|n| &operands[n].1).unwrap_or(at), e.message)
+        }
+    }
+
+    /// points the jump at `address` to `target`
+    fn patch(&mut self, address: u32, target: u32, at: &Position) -> Result<(), AsmError> {
+        if target > 0xfff { return error(at, format!("{:#x} does not fit into 12 bits, only i := long reaches beyond 0xfff", target)) }
+        let n = (address - ORIGIN) as usize;
+        self.rom[n] = self.rom[n] & 0xf0 | (target >> 8) as u8;
+        self.rom[n + 1] = target as u8;
+        Ok(())
+    }
+
+    fn register(&self, t: &Token) -> Option<u8> {
+        if let Some(r) = self.aliases.get(&t.text) { return Some(*r) }
+        if is_register(&t.text) { u8::from_str_radix(&t.text[1..], 16).ok() } else { None }
+    }
+
+    fn register_arg(&mut self) -> Result<(Operand, Position), AsmError> {
+        let t = self.next("a register")?;
+        match self.register(&t) {
+            Some(r) => Ok((Operand::Register(r), t.at)),
+            None => error(&t.at, format!("{} is not a register", t.text))
+        }
+    }
+
+    /// a literal, constant or label that is defined already
+    fn number(&self, t: &Token) -> Option<i64> {
+        literal(&t.text)
+            .or_else(|| self.constants.get(&t.text).map(|v| v.floor() as i64))
+            .or_else(|| self.labels.get(&t.text).map(|(v, _)| *v as i64))
+    }
+
+    fn value(&self, t: &Token) -> Result<i64, AsmError> {
+        match self.number(t) {
+            Some(v) => Ok(v),
+            None if self.is_name(&t.text) => error(&t.at, format!("undefined name {}", t.text)),
+            None => error(&t.at, format!("{} is not a number", t.text))
+        }
+    }
+
+    /// the address `t` names, or 0 and a note to fill it in at `address` once the label is defined
+    fn address(&mut self, t: &Token, address: u32, fixup: Fixup) -> Result<i64, AsmError> {
+        if let Some(v) = self.number(t) { return Ok(v) }
+        if !self.is_name(&t.text) { return error(&t.at, format!("{} is not an address", t.text)) }
+        self.references.push(Reference { address, fixup, name: t.text.clone(), at: t.at.clone() });
+        Ok(0)
+    }
+
+    fn is_name(&self, text: &str) -> bool {
+        let mut chars = text.chars();
+        chars.next().map(|c| c.is_alphabetic() || c == '_').unwrap_or(false)
+            && chars.all(|c| c.is_alphanumeric() || "_-.".contains(c))
+            && !is_register(text) && !KEYWORDS.contains(&text)
+    }
+
+    /// the name a directive defines
+    fn new_name(&mut self) -> Result<Token, AsmError> {
+        let t = self.next("a name")?;
+        if !self.is_name(&t.text) { return error(&t.at, format!("{} cannot be a name", t.text)) }
+        if let Some((_, first)) = self.labels.get(&t.text) {
+            return error(&t.at, format!("{} is already defined at {}", t.text, first))
+        }
+        if self.constants.contains_key(&t.text) || self.aliases.contains_key(&t.text) || self.macros.contains_key(&t.text) {
+            return error(&t.at, format!("{} is already defined", t.text))
+        }
+        Ok(t)
+    }
+
+    fn label(&mut self, name: Token, address: u32) {
+        self.labels.insert(name.text, (address, name.at));
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let t = self.next("a statement")?;
+        let at = t.at.clone();
+        if let Some(x) = self.register(&t) {
+            return self.assignment((Operand::Register(x), at))
+        }
+        let word = |w: &str| (Operand::Word(w.to_string()), at.clone());
+        match t.text.as_str() {
+            ":" => {
+                let name = self.new_name()?;
+                self.label(name, self.here)
+            },
+            ":next" => { // the second byte of the next instruction, for self-modifying code
+                let name = self.new_name()?;
+                self.label(name, self.here + 1)
+            },
+            ":const" => {
+                let name = self.new_name()?;
+                let value = self.next("a value")?;
+                let value = self.value(&value)?;
+                self.constants.insert(name.text, value as f64);
+            },
+            ":calc" => {
+                let name = self.new_name()?;
+                let value = self.calc()?;
+                self.constants.insert(name.text, value);
+            },
+            ":alias" => {
+                let name = self.new_name()?;
+                let register = if self.peek() == Some("{") { self.calc()? as i64 } else { match self.register_arg()?.0 {
+                    Operand::Register(r) => r as i64,
+                    _ => unreachable!("register_arg only returns registers")
+                } };
+                if !(0..16).contains(&register) { return error(&name.at, format!("v{} is not a register", register)) }
+                self.aliases.insert(name.text, register as u8);
+            },
+            ":unpack" => {
+                let high = self.next("a nibble or long")?;
+                let long = high.text == "long";
+                let nibble = if long { 0 } else { self.value(&high)? };
+                if !(0..16).contains(&nibble) { return error(&high.at, format!("{} does not fit into a nibble", nibble)) }
+                let name = self.next("an address")?;
+                let address = self.address(&name, self.here, Fixup::Unpack(long))?;
+                let high = if long { address >> 8 & 0xff } else { nibble << 4 | address >> 8 & 0xf };
+                self.emit(("LD", vec![(Operand::Register(0), at.clone()), (Operand::Value(high), name.at.clone())]), &at)?;
+                self.emit(("LD", vec![(Operand::Register(1), at.clone()), (Operand::Value(address & 0xff), name.at)]), &at)?;
+            },
+            ":org" => {
+                let address = self.next("an address")?;
+                let value = self.value(&address)?;
+                if !(ORIGIN as i64..=0xffff).contains(&value) { return error(&address.at, format!("{:#x} is not between 0x200 and 0xffff", value)) }
+                self.here = value as u32;
+            },
+            ":byte" => {
+                let (value, at) = if self.peek() == Some("{") { (self.calc()?.floor() as i64, at) } else {
+                    let t = self.next("a value")?;
+                    (self.value(&t)?, t.at)
+                };
+                if !(-128..=255).contains(&value) { return error(&at, format!("{} does not fit into a byte", value)) }
+                self.byte(value as u8, &at)?
+            },
+            ":pointer" => {
+                let value = if self.peek() == Some("{") { self.calc()?.floor() as i64 } else {
+                    let t = self.next("an address")?;
+                    self.address(&t, self.here, Fixup::Long)?
+                };
+                self.byte((value >> 8) as u8, &at)?;
+                self.byte(value as u8, &at)?
+            },
+            ":macro" => {
+                let name = self.new_name()?;
+                let mut params = Vec::new();
+                while self.peek() != Some("{") {
+                    params.push(self.next("{")?.text)
+                }
+                let body = self.braces()?;
+                self.macros.insert(name.text, Macro { params, body });
+            },
+            ":assert" => {
+                let message = if self.peek().map(|p| p.starts_with('"')).unwrap_or(false) { self.next("a message")?.text.trim_matches('"').to_string() } else { "assertion failed".to_string() };
+                if self.calc()? == 0.0 { return error(&at, message) }
+            },
+            ":breakpoint" | ":proto" => { self.next("a name")?; },
+            ":monitor" => {
+                self.next("an address")?;
+                if self.peek() == Some("{") { self.braces()?; } else { self.next("a length or format")?; }
+            },
+            ":call" => {
+                let target = self.next("an address")?;
+                let value = self.address(&target, self.here, Fixup::Addr)?;
+                self.emit(("CALL", vec![(Operand::Value(value), target.at)]), &at)?
+            },
+            "clear" => self.emit(("CLS", vec![]), &at)?,
+            "return" | ";" => self.emit(("RET", vec![]), &at)?,
+            "hires" => self.emit(("HIGH", vec![]), &at)?,
+            "lores" => self.emit(("LOW", vec![]), &at)?,
+            "exit" => self.emit(("EXIT", vec![]), &at)?,
+            "scroll-left" => self.emit(("SCL", vec![]), &at)?,
+            "scroll-right" => self.emit(("SCR", vec![]), &at)?,
+            "audio" => self.emit(("AUDIO", vec![]), &at)?,
+            "scroll-down" | "scroll-up" | "plane" => {
+                let n = self.next("a number")?;
+                let value = self.value(&n)?;
+                let mnemonic = match t.text.as_str() { "scroll-down" => "SCD", "scroll-up" => "SCU", _ => "PLANE" };
+                self.emit((mnemonic, vec![(Operand::Value(value), n.at)]), &at)?
+            },
+            "bcd" => {
+                let x = self.register_arg()?;
+                self.emit(("LD", vec![word("B"), x]), &at)?
+            },
+            "save" | "load" => {
+                let x = self.register_arg()?;
+                if self.peek() == Some("-") { // XO-CHIP ranges
+                    self.next("-")?;
+                    let y = self.register_arg()?;
+                    self.emit((if t.text == "save" { "SAVE" } else { "LOAD" }, vec![x, y]), &at)?
+                } else if t.text == "save" {
+                    self.emit(("LD", vec![word("[I]"), x]), &at)?
+                } else {
+                    self.emit(("LD", vec![x, word("[I]")]), &at)?
+                }
+            },
+            "saveflags" => {
+                let x = self.register_arg()?;
+                self.emit(("LD", vec![word("R"), x]), &at)?
+            },
+            "loadflags" => {
+                let x = self.register_arg()?;
+                self.emit(("LD", vec![x, word("R")]), &at)?
+            },
+            "sprite" => {
+                let x = self.register_arg()?;
+                let y = self.register_arg()?;
+                let n = self.next("a height")?;
+                let height = self.value(&n)?;
+                self.emit(("DRW", vec![x, y, (Operand::Value(height), n.at)]), &at)?
+            },
+            "jump" | "jump0" | "native" => {
+                let target = self.next("an address")?;
+                let value = self.address(&target, self.here, Fixup::Addr)?;
+                let operand = (Operand::Value(value), target.at);
+                match t.text.as_str() {
+                    "jump" => self.emit(("JP", vec![operand]), &at)?,
+                    "jump0" => self.emit(("JP", vec![(Operand::Register(0), at.clone()), operand]), &at)?,
+                    _ => self.emit(("SYS", vec![operand]), &at)?
+                }
+            },
+            "i" => {
+                let op = self.next(":= or +=")?;
+                match op.text.as_str() {
+                    ":=" => {
+                        let source = self.next("an address")?;
+                        match source.text.as_str() {
+                            "hex" | "bighex" => {
+                                let x = self.register_arg()?;
+                                self.emit(("LD", vec![word(if source.text == "hex" { "F" } else { "HF" }), x]), &at)?
+                            },
+                            "long" => {
+                                let target = self.next("an address")?;
+                                let value = self.address(&target, self.here + 2, Fixup::Long)?;
+                                self.emit(("LD", vec![word("I"), (Operand::Long(value), target.at)]), &at)?
+                            },
+                            _ => {
+                                let value = self.address(&source, self.here, Fixup::Addr)?;
+                                self.emit(("LD", vec![word("I"), (Operand::Value(value), source.at)]), &at)?
+                            }
+                        }
+                    },
+                    "+=" => {
+                        let x = self.register_arg()?;
+                        self.emit(("ADD", vec![word("I"), x]), &at)?
+                    },
+                    other => return error(&op.at, format!("i can only be assigned with := or +=, not {}", other))
+                }
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register_arg()?;
+                match t.text.as_str() {
+                    "delay" => self.emit(("LD", vec![word("DT"), x]), &at)?,
+                    "buzzer" => self.emit(("LD", vec![word("ST"), x]), &at)?,
+                    _ => self.emit(("PITCH", vec![x]), &at)?
+                }
+            },
+            "if" => {
+                let (setup, when_true, when_false) = self.condition()?;
+                for instruction in setup { self.emit(instruction, &at)? }
+                let then = self.next("then or begin")?;
+                match then.text.as_str() {
+                    "then" => {
+                        self.emit(when_false, &at)?;
+                        self.statement()?
+                    },
+                    "begin" => {
+                        self.emit(when_true, &at)?;
+                        let jump = self.here;
+                        self.emit(("JP", vec![(Operand::Value(0), at.clone())]), &at)?;
+                        self.blocks.push(Block::If { jump, at });
+                    },
+                    other => return error(&then.at, format!("expected then or begin but found {}", other))
+                }
+            },
+            "else" => match self.blocks.pop() {
+                Some(Block::If { jump, at: open }) => {
+                    let skip = self.here;
+                    self.emit(("JP", vec![(Operand::Value(0), at.clone())]), &at)?;
+                    self.patch(jump, self.here, &at)?;
+                    self.blocks.push(Block::If { jump: skip, at: open });
+                },
+                other => {
+                    self.blocks.extend(other);
+                    return error(&at, "else without if ... begin".to_string())
+                }
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => self.patch(jump, self.here, &at)?,
+                other => {
+                    self.blocks.extend(other);
+                    return error(&at, "end without if ... begin".to_string())
+                }
+            },
+            "loop" => self.blocks.push(Block::Loop { start: self.here, whiles: Vec::new(), at }),
+            "while" => {
+                let (setup, when_true, _) = self.condition()?;
+                for instruction in setup { self.emit(instruction, &at)? }
+                self.emit(when_true, &at)?;
+                let jump = self.here;
+                self.emit(("JP", vec![(Operand::Value(0), at.clone())]), &at)?;
+                match self.blocks.iter_mut().rev().find_map(|b| match b { Block::Loop { whiles, .. } => Some(whiles), _ => None }) {
+                    Some(whiles) => whiles.push(jump),
+                    None => return error(&at, "while outside of loop ... again".to_string())
+                }
+            },
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, whiles, .. }) => {
+                    self.emit(("JP", vec![(Operand::Value(start as i64), at.clone())]), &at)?;
+                    for jump in whiles { self.patch(jump, self.here, &at)? }
+                },
+                Some(Block::If { at: open, .. }) => return error(&at, format!("again before the end of the if ... begin at {}", open)),
+                None => return error(&at, "again without loop".to_string())
+            },
+            name if self.macros.contains_key(name) => self.expand(&t)?,
+            _ => match self.number(&t) {
+                Some(value) if literal(&t.text).is_some() || self.constants.contains_key(&t.text) => { // sprite data and the like
+                    if !(-128..=255).contains(&value) { return error(&at, format!("{} does not fit into a byte", value)) }
+                    self.byte(value as u8, &at)?
+                },
+                _ if self.is_name(&t.text) => { // a subroutine call, the label may come later
+                    let value = self.address(&t, self.here, Fixup::Addr)?;
+                    self.emit(("CALL", vec![(Operand::Value(value), at.clone())]), &at)?
+                },
+                _ => return error(&at, format!("{} is not a statement", t.text))
+            }
+        }
+        Ok(())
+    }
+
+    /// `vx := ...`, `vx += ...` and the other register operations
+    fn assignment(&mut self, x: (Operand, Position)) -> Result<(), AsmError> {
+        let op = self.next("an operator")?;
+        let source = self.next("a value")?;
+        let at = x.1.clone();
+        let y = match self.register(&source) {
+            Some(r) => {
+                let mnemonic = match op.text.as_str() {
+                    ":=" => "LD",
+                    "+=" => "ADD",
+                    "-=" => "SUB",
+                    "=-" => "SUBN",
+                    "|=" => "OR",
+                    "&=" => "AND",
+                    "^=" => "XOR",
+                    ">>=" => "SHR",
+                    "<<=" => "SHL",
+                    other => return error(&op.at, format!("{} is not an operator", other))
+                };
+                return self.emit((mnemonic, vec![x, (Operand::Register(r), source.at)]), &at)
+            },
+            None => source
+        };
+        match (op.text.as_str(), y.text.as_str()) {
+            (":=", "key") => self.emit(("LD", vec![x, (Operand::Word("K".to_string()), y.at)]), &at),
+            (":=", "delay") => self.emit(("LD", vec![x, (Operand::Word("DT".to_string()), y.at)]), &at),
+            (":=", "random") => {
+                let mask = self.next("a mask")?;
+                let value = self.value(&mask)?;
+                self.emit(("RND", vec![x, (Operand::Value(value), mask.at)]), &at)
+            },
+            (":=", _) | ("+=", _) => {
+                let value = self.value(&y)?;
+                self.emit((if op.text == ":=" { "LD" } else { "ADD" }, vec![x, (Operand::Value(value), y.at)]), &at)
+            },
+            ("-=", _) => {
+                let value = self.value(&y)?;
+                if !(0..=255).contains(&value) { return error(&y.at, format!("{} does not fit into a byte", value)) }
+                self.emit(("ADD", vec![x, (Operand::Value((256 - value) & 0xff), y.at)]), &at)
+            },
+            _ => error(&y.at, format!("{} needs a register, not {}", op.text, y.text))
+        }
+    }
+
+    /// instructions to prepare a test, one that skips if the condition holds and one that skips if not
+    fn condition(&mut self) -> Result<(Vec<Instruction>, Instruction, Instruction), AsmError> {
+        let x = self.register_arg()?;
+        let op = self.next("a comparison")?;
+        match op.text.as_str() {
+            "key" => return Ok((vec![], ("SKP", vec![x.clone()]), ("SKNP", vec![x]))),
+            "-key" => return Ok((vec![], ("SKNP", vec![x.clone()]), ("SKP", vec![x]))),
+            _ => {}
+        }
+        let source = self.next("a value")?;
+        let y = match self.register(&source) {
+            Some(r) => (Operand::Register(r), source.at),
+            None => (Operand::Value(self.value(&source)?), source.at)
+        };
+        let vf = (Operand::Register(0xf), op.at.clone());
+        match op.text.as_str() {
+            "==" => Ok((vec![], ("SE", vec![x.clone(), y.clone()]), ("SNE", vec![x, y]))),
+            "!=" => Ok((vec![], ("SNE", vec![x.clone(), y.clone()]), ("SE", vec![x, y]))),
+            "<" | ">" | "<=" | ">=" => {
+                // VF := a - b leaves VF at 1 if a >= b, so a < b if it is 0 and b < a if a is swapped with b
+                let (a, b) = if op.text == "<" || op.text == ">=" { (x, y) } else { (y, x) };
+                let setup = match b.0 {
+                    Operand::Register(_) => vec![("LD", vec![vf.clone(), a]), ("SUB", vec![vf.clone(), b])],
+                    _ => vec![("LD", vec![vf.clone(), b]), ("SUBN", vec![vf.clone(), a])]
+                };
+                let flag = (Operand::Value(if op.text.len() == 1 { 0 } else { 1 }), op.at.clone());
+                Ok((setup, ("SE", vec![vf.clone(), flag.clone()]), ("SNE", vec![vf, flag])))
+            },
+            other => error(&op.at, format!("{} is not a comparison", other))
+        }
+    }
+
+    /// the tokens between `{` and its `}`
+    fn braces(&mut self) -> Result<Vec<Token>, AsmError> {
+        let open = self.expect("{")?;
+        let mut depth = 1;
+        let mut body = Vec::new();
+        loop {
+            let t = match self.tokens.get(self.pos) {
+                Some(t) => t.clone(),
+                None => return error(&open.at, "{ without }".to_string())
+            };
+            self.pos += 1;
+            match t.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 1 => return Ok(body),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(t);
+        }
+    }
+
+    /// puts the body of a macro with the arguments that follow `name` in place of the call
+    fn expand(&mut self, name: &Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > 100_000 { return error(&name.at, format!("{} expands without end", name.text)) }
+        let m = self.macros[&name.text].clone();
+        let mut args = Vec::new();
+        for param in &m.params {
+            args.push(self.next(&format!("argument {} of {}", param, name.text))?)
+        }
+        let body: Vec<Token> = m.body.iter().map(|t| match m.params.iter().position(|p| *p == t.text) {
+            Some(n) => args[n].clone(),
+            None => t.clone()
+        }).collect();
+        self.tokens.splice(self.pos..self.pos, body);
+        Ok(())
+    }
+
+    /// `{ expression }`, evaluated from right to left without precedence like in Octo
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        self.expect("{")?;
+        let value = self.calc_expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expression(&mut self) -> Result<f64, AsmError> {
+        const BINARY: [&str; 19] = ["+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", "<=", "==", "!=", ">=", ">"];
+        let left = self.calc_term()?;
+        let op = match self.peek() {
+            Some(op) if BINARY.contains(&op) => self.next("an operator")?,
+            _ => return Ok(left)
+        };
+        let right = self.calc_expression()?;
+        let (l, r) = (left as i64, right as i64);
+        Ok(match op.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" | "%" if right == 0.0 => return error(&op.at, "division by zero".to_string()),
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (l & r) as f64,
+            "|" => (l | r) as f64,
+            "^" => (l ^ r) as f64,
+            "<<" => l.checked_shl(r as u32).unwrap_or(0) as f64,
+            ">>" => l.checked_shr(r as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64 as f64,
+            "<=" => (left <= right) as i64 as f64,
+            "==" => (left == right) as i64 as f64,
+            "!=" => (left != right) as i64 as f64,
+            ">=" => (left >= right) as i64 as f64,
+            _ => (left > right) as i64 as f64
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, AsmError> {
+        let t = self.next("a value")?;
+        let unary: Option<fn(f64) -> f64> = match t.text.as_str() {
+            "-" => Some(|x| -x),
+            "~" => Some(|x| !(x as i64) as f64),
+            "!" => Some(|x| (x == 0.0) as i64 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None
+        };
+        if let Some(f) = unary { return Ok(f(self.calc_term()?)) }
+        match t.text.as_str() {
+            "(" => {
+                let value = self.calc_expression()?;
+                self.expect(")")?;
+                Ok(value)
+            },
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => match self.constants.get(&t.text) {
+                Some(value) => Ok(*value),
+                None => match t.text.parse::<f64>() {
+                    Ok(value) if t.text.starts_with(|c: char| c.is_ascii_digit()) => Ok(value), // 0.5
+                    _ => Ok(self.value(&t)? as f64)
+                }
+            }
+        }
+    }
+
+    /// fills in the addresses of labels that were used before they were defined
+    fn finish(mut self) -> Result<Assembly, AsmError> {
+        match self.blocks.last() {
+            Some(Block::If { at, .. }) => return error(at, "begin has no end".to_string()),
+            Some(Block::Loop { at, .. }) => return error(at, "loop has no again".to_string()),
+            None => {}
+        }
+        for r in std::mem::take(&mut self.references) {
+            let value = match self.labels.get(&r.name).map(|(v, _)| *v as i64).or_else(|| self.constants.get(&r.name).map(|v| v.floor() as i64)) {
+                Some(value) => value,
+                None if r.name == "main" && r.address == ORIGIN => return error(&r.at, "the program has no : main to start at".to_string()),
+                None => return error(&r.at, format!("undefined name {}", r.name))
+            };
+            let n = (r.address - ORIGIN) as usize;
+            match r.fixup {
+                Fixup::Addr => self.patch(r.address, value as u32, &r.at)?,
+                Fixup::Long => self.rom[n..n + 2].copy_from_slice(&(value as u16).to_be_bytes()),
+                Fixup::Unpack(long) => {
+                    self.rom[n + 1] |= if long { (value >> 8) as u8 } else { (value >> 8) as u8 & 0xf };
+                    self.rom[n + 3] = value as u8;
+                }
+            }
+        }
+        let mut symbols: BTreeMap<String, i64> = self.labels.into_iter().map(|(name, (value, _))| (name, value as i64)).collect();
+        symbols.extend(self.constants.into_iter().map(|(name, value)| (name, value.floor() as i64)));
+        Ok(Assembly { rom: self.rom, symbols })
+    }
+}
@@ -0,0 +1,78 @@
+use crate::cartridge::Cartridge;
+
+/// how FX55/FX65 leave the I register after dumping or loading V0--VX
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum IndexIncrement {
+    Unchanged,
+    ByX,
+    ByXPlusOne
+}
+
+/// what happens when I plus an offset points past the end of memory
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum MemoryOverflow {
+    Wrap,
+    Fault
+}
+
+/// behaviour of opcodes (and size of memory) that differ between CHIP-8 interpreters
+#[derive(Debug,Clone,PartialEq)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,  // 8XY6/8XYE shift VY into VX instead of shifting VX in place
+    pub load_store_index: IndexIncrement,
+    pub jump_uses_vx: bool,   // BXNN jumps to XNN+VX instead of BNNN to NNN+V0
+    pub logic_resets_vf: bool,  // 8XY1/8XY2/8XY3 set VF to 0
+    pub clip_sprites: bool,   // sprites are cut off at the display edges instead of wrapping around
+    pub display_wait: bool,   // DXYN waits for the next 60 Hz interrupt before drawing
+    pub lores_dxy0_8x16: bool, // DXY0 draws an 8x16 sprite in low resolution instead of a 16x16 one
+    pub memory_size: usize,
+    pub memory_overflow: MemoryOverflow
+}
+
+impl Quirks {
+    pub const PRESETS: [&'static str; 4] = ["vip", "chip48", "schip", "xo-chip"];
+
+    /// original COSMAC VIP interpreter
+    pub fn vip() -> Self {
+        Self { shift_uses_vy: true, load_store_index: IndexIncrement::ByXPlusOne, jump_uses_vx: false,
+            logic_resets_vf: true, clip_sprites: true, display_wait: true, lores_dxy0_8x16: false,
+            memory_size: Cartridge::MEMORY_SIZE, memory_overflow: MemoryOverflow::Wrap }
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Self {
+        Self { shift_uses_vy: false, load_store_index: IndexIncrement::ByX, jump_uses_vx: true,
+            logic_resets_vf: false, clip_sprites: true, display_wait: false, lores_dxy0_8x16: false,
+            memory_size: Cartridge::MEMORY_SIZE, memory_overflow: MemoryOverflow::Wrap }
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn schip() -> Self {
+        Self { shift_uses_vy: false, load_store_index: IndexIncrement::Unchanged, jump_uses_vx: true,
+            logic_resets_vf: false, clip_sprites: true, display_wait: false, lores_dxy0_8x16: true,
+            memory_size: Cartridge::MEMORY_SIZE, memory_overflow: MemoryOverflow::Wrap }
+    }
+
+    /// XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Self {
+        Self { shift_uses_vy: true, load_store_index: IndexIncrement::ByXPlusOne, jump_uses_vx: false,
+            logic_resets_vf: false, clip_sprites: false, display_wait: false, lores_dxy0_8x16: false,
+            memory_size: Cartridge::XO_CHIP_MEMORY_SIZE, memory_overflow: MemoryOverflow::Wrap }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vip" => Some(Self::vip()),
+            "chip48" => Some(Self::chip48()),
+            "schip" => Some(Self::schip()),
+            "xo-chip" => Some(Self::xo_chip()),
+            _ => None
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::vip()
+    }
+}
@@ -0,0 +1,121 @@
+/// where CXNN gets its random numbers from; the state is kept in save states so that runs replay exactly
+pub trait RandomSource {
+    fn next_u8(&mut self) -> u8;
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+/// xorshift generator, the default
+pub struct Xorshift {
+    state: u32
+}
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        let mut x = Self { state: 0 };
+        x.set_state(seed);
+        x
+    }
+}
+
+impl RandomSource for Xorshift {
+    fn next_u8(&mut self) -> u8 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        (x >> 24) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state as u64
+    }
+
+    fn set_state(&mut self, state: u64) {
+        let folded = (state ^ (state >> 32)) as u32;
+        self.state = if folded == 0 { 0x2545_f491 } else { folded } // xorshift never leaves 0
+    }
+}
+
+/// the CXNN routine of the COSMAC VIP interpreter: R9 counts up with every call, its low byte picks a
+/// byte of the interpreter's second page (0x100 -- 0x1FF), which is added to its high byte, and that
+/// sum rotated right through the carry and added once more becomes the new high byte and the number
+pub struct VipRandom {
+    r9: u16
+}
+
+impl VipRandom {
+    /// the interpreter's second page, transcribed from a listing of its code
+    const PAGE: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x45, 0xa3, 0x98, 0x56, 0xd4, 0xf8, 0x81, 0xbc, 0xf8, 0x95, 0xac, 0x22,
+    0xdc, 0x12, 0x56, 0xd4, 0x06, 0xb8, 0xd4, 0x06, 0xa8, 0xd4, 0x64, 0x0a, 0x01, 0xe6, 0x8a, 0xf4,
+    0xaa, 0x3b, 0x28, 0x9a, 0xfc, 0x01, 0xba, 0xd4, 0xf8, 0x81, 0xba, 0x06, 0xfa, 0x0f, 0xaa, 0x0a,
+    0xaa, 0xd4, 0xe6, 0x06, 0xbf, 0x93, 0xbe, 0xf8, 0x1b, 0xae, 0x2a, 0x1a, 0xf8, 0x00, 0x5a, 0x0e,
+    0xf5, 0x3b, 0x4b, 0x56, 0x0a, 0xfc, 0x01, 0x5a, 0x30, 0x40, 0x4e, 0xf6, 0x3b, 0x3c, 0x9f, 0x56,
+    0x2a, 0x2a, 0xd4, 0x00, 0x22, 0x86, 0x52, 0xf8, 0xf0, 0xa7, 0x07, 0x5a, 0x87, 0xf3, 0x17, 0x1a,
+    0x3a, 0x5b, 0x12, 0xd4, 0x22, 0x86, 0x52, 0xf8, 0xf0, 0xa7, 0x0a, 0x57, 0x87, 0xf3, 0x17, 0x1a,
+    0x3a, 0x6b, 0x12, 0xd4, 0x15, 0x85, 0x22, 0x73, 0x95, 0x52, 0x25, 0x45, 0xa5, 0x86, 0xfa, 0x0f,
+    0xb5, 0xd4, 0x45, 0xe6, 0xf3, 0x3a, 0x82, 0x15, 0x15, 0xd4, 0x45, 0xe6, 0xf3, 0x3a, 0x88, 0xd4,
+    0x45, 0x07, 0x30, 0x8c, 0x45, 0x07, 0x30, 0x84, 0xe6, 0x62, 0x26, 0x45, 0xa3, 0x36, 0x88, 0xd4,
+    0x3e, 0x88, 0xd4, 0xf8, 0xf0, 0xa7, 0xe7, 0x45, 0xf4, 0xa5, 0x86, 0xfa, 0x0f, 0x3b, 0xb2, 0xfc,
+    0x01, 0xb5, 0xd4, 0x45, 0x56, 0xd4, 0x45, 0xe6, 0xf4, 0x56, 0xd4, 0x45, 0xfa, 0x0f, 0x3a, 0xc4,
+    0x07, 0x56, 0xd4, 0xaf, 0x22, 0xf8, 0xd3, 0x73, 0x8f, 0xf9, 0xf0, 0x52, 0xe6, 0x07, 0xd2, 0x56,
+    0xf8, 0xff, 0xa6, 0xf8, 0x00, 0x7e, 0x56, 0xd4, 0x19, 0x89, 0xae, 0x93, 0xbe, 0x99, 0xee, 0xf4,
+    0x56, 0x76, 0xe6, 0xf4, 0xb9, 0x56, 0x45, 0xf2, 0x56, 0xd4, 0x45, 0xaa, 0x86, 0xfa, 0x0f, 0xba,
+    0xd4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe0, 0x00, 0x4b,
+    ];
+
+    pub fn new(seed: u64) -> Self {
+        let mut x = Self { r9: 0 };
+        x.set_state(seed);
+        x
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn next_u8(&mut self) -> u8 {
+        self.r9 = self.r9.wrapping_add(1);
+        let [high, low] = self.r9.to_be_bytes();
+        let (sum, carry) = high.overflowing_add(Self::PAGE[low as usize]);
+        let rotated = sum >> 1 | (carry as u8) << 7;
+        let number = rotated.wrapping_add(sum);
+        self.r9 = u16::from_be_bytes([number, low]);
+        number
+    }
+
+    fn state(&self) -> u64 {
+        self.r9 as u64
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.r9 = state as u16
+    }
+}
+
+pub const NAMES: [&str; 2] = ["xorshift", "vip"];
+
+pub fn from_name(name: &str, seed: u64) -> Option<Box<dyn RandomSource>> {
+    match name {
+        "xorshift" => Some(Box::new(Xorshift::new(seed))),
+        "vip" => Some(Box::new(VipRandom::new(seed))),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vip_numbers_repeat_from_a_saved_state() {
+        let mut rng = VipRandom::new(0x1234);
+        let numbers: Vec<u8> = (0..8).map(|_| rng.next_u8()).collect();
+        assert_eq!(numbers, [247, 143, 74, 151, 231, 153, 12, 134]);
+        let state = rng.state();
+        let ahead: Vec<u8> = (0..4).map(|_| rng.next_u8()).collect();
+        let mut restored = from_name("vip", 0).unwrap();
+        restored.set_state(state);
+        assert_eq!((0..4).map(|_| restored.next_u8()).collect::<Vec<u8>>(), ahead);
+    }
+}
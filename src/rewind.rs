@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+
+/// ring buffer of recent save states for stepping backwards in time
+///
+/// Only the newest state is kept in full, every older one is stored as the difference to its
+/// successor, which is small since most of the memory and display stay the same between frames.
+pub struct Rewind {
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>, // oldest first, the last one turns `latest` into the state before it
+    capacity: usize
+}
+
+impl Rewind {
+    /// keeps at most `capacity` states, e.g. one per frame
+    pub fn new(capacity: usize) -> Self {
+        Self { latest: None, deltas: VecDeque::new(), capacity }
+    }
+
+    /// adds a state from `Chip8State::save_state`, dropping the oldest one if the buffer is full
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 { return }
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(diff(&state, &previous));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state)
+    }
+
+    /// drops the newest state, which is the machine as it is now, and returns the one before it,
+    /// which becomes the newest; `None` if there is nothing older
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        let previous = patch(self.latest.as_ref()?, &delta);
+        self.latest = Some(previous.clone());
+        Some(previous)
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear()
+    }
+
+    /// bytes taken by the stored states
+    pub fn size(&self) -> usize {
+        self.latest.as_ref().map(|l| l.len()).unwrap_or(0) + self.deltas.iter().map(|d| d.len()).sum::<usize>()
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, mut n: usize) {
+    // LEB128, runs are mostly short
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7
+    }
+    out.push(n as u8)
+}
+
+fn read_len(delta: &[u8], pos: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let b = delta[*pos];
+        *pos += 1;
+        n |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 { return n }
+        shift += 7
+    }
+}
+
+/// `to` as alternating runs of bytes equal to `from` (skipped) and differing bytes (stored);
+/// states of a different length are stored in full
+fn diff(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if from.len() != to.len() {
+        out.push(0);
+        out.extend_from_slice(to);
+        return out
+    }
+    out.push(1);
+    let mut pos = 0;
+    while pos < to.len() {
+        let same = from[pos..].iter().zip(&to[pos..]).take_while(|(a, b)| a == b).count();
+        pos += same;
+        // a differing run only ends at a few equal bytes, which are cheaper to store than a new run
+        let mut end = pos;
+        while end < to.len() && from[end..].iter().zip(&to[end..]).take(4).any(|(a, b)| a != b) {
+            end += 1
+        }
+        write_len(&mut out, same);
+        write_len(&mut out, end - pos);
+        out.extend_from_slice(&to[pos..end]);
+        pos = end
+    }
+    out
+}
+
+fn patch(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    if delta[0] == 0 {
+        return delta[1..].to_vec()
+    }
+    let mut to = from.to_vec();
+    let mut pos = 1;
+    let mut at = 0;
+    while pos < delta.len() {
+        at += read_len(delta, &mut pos);
+        let n = read_len(delta, &mut pos);
+        to[at..at + n].copy_from_slice(&delta[pos..pos + n]);
+        pos += n;
+        at += n
+    }
+    to
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// states of a few KiB where some bytes change every frame, and sometimes the length
+    fn states(n: usize) -> Vec<Vec<u8>> {
+        let mut state: Vec<u8> = (0..4096).map(|n| (n * 7) as u8).collect();
+        let mut seed = 1u32;
+        (0..n).map(|frame| {
+            for _ in 0..frame % 40 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let at = (seed >> 8) as usize % state.len();
+                state[at] = (seed >> 24) as u8;
+            }
+            if frame % 17 == 16 { state.push(frame as u8) }
+            state.clone()
+        }).collect()
+    }
+
+    #[test]
+    fn pops_the_pushed_states_in_reverse() {
+        let states = states(100);
+        let mut rewind = Rewind::new(1000);
+        states.iter().for_each(|s| rewind.push(s.clone()));
+        assert!(rewind.size() < states.iter().map(|s| s.len()).sum::<usize>() / 4);
+        for expected in states.iter().rev().skip(1) {
+            assert_eq!(rewind.pop().as_ref(), Some(expected));
+        }
+        assert_eq!(rewind.pop(), None);
+        assert_eq!(rewind.len(), 1); // the oldest state, which is now the current one
+    }
+
+    #[test]
+    fn keeps_at_most_capacity_states() {
+        let states = states(50);
+        let mut rewind = Rewind::new(20);
+        states.iter().for_each(|s| rewind.push(s.clone()));
+        assert_eq!(rewind.len(), 20);
+        for expected in states[30..49].iter().rev() {
+            assert_eq!(rewind.pop().as_ref(), Some(expected));
+        }
+        assert_eq!(rewind.pop(), None);
+        assert!(Rewind::new(0).is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+
+/// why a save state could not be restored
+#[derive(Debug,Clone,PartialEq)]
+pub enum SnapshotError {
+    NotASnapshot,
+    UnsupportedVersion(u8),
+    Truncated,
+    Invalid(&'static str)
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a chip8-emu save state"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "save state version {} is not supported (expected {})", v, VERSION),
+            SnapshotError::Truncated => write!(f, "save state is truncated"),
+            SnapshotError::Invalid(what) => write!(f, "save state is invalid: {}", what)
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+const MAGIC: &[u8; 4] = b"C8ST";
+/// bumped whenever the layout below changes, older files are rejected rather than misread
+pub const VERSION: u8 = 3;
+
+/// builds a save state: the header followed by little-endian fields in a fixed order
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::with_header(MAGIC, VERSION)
+    }
+
+    /// for other files in the same format, e.g. movies
+    pub fn with_header(magic: &[u8; 4], version: u8) -> Self {
+        let mut bytes = magic.to_vec();
+        bytes.push(version);
+        Self { bytes }
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.bytes.push(val)
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.bytes.extend_from_slice(&val.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.bytes.extend_from_slice(&val.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.bytes.extend_from_slice(&val.to_le_bytes())
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8)
+    }
+
+    /// length prefixed
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.bytes.extend_from_slice(val)
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// reads the fields back in the order `StateWriter` wrote them
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, SnapshotError> {
+        Self::with_header(bytes, MAGIC, VERSION)
+    }
+
+    pub fn with_header(bytes: &'a [u8], magic: &[u8; 4], version: u8) -> Result<Self, SnapshotError> {
+        let mut reader = Self { bytes };
+        if reader.take(magic.len()).map_err(|_| SnapshotError::NotASnapshot)? != magic {
+            return Err(SnapshotError::NotASnapshot)
+        }
+        match reader.u8()? {
+            v if v == version => Ok(reader),
+            v => Err(SnapshotError::UnsupportedVersion(v))
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if n > self.bytes.len() { return Err(SnapshotError::Truncated) }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes([self.u8()?, self.u8()?, self.u8()?, self.u8()?]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Invalid("flag is neither 0 nor 1"))
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    /// everything has been read, trailing bytes mean the file is not what it claims to be
+    pub fn finish(self) -> Result<(), SnapshotError> {
+        match self.bytes.is_empty() {
+            true => Ok(()),
+            false => Err(SnapshotError::Invalid("trailing data"))
+        }
+    }
+}
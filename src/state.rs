@@ -1,8 +1,8 @@
-use rand::Rng;
-use rand::rngs::ThreadRng;
-
-use crate::instruction::{Varset, Instruction, Operation};
-use crate::cartridge::Cartridge;
+use crate::instruction::{Varset, Instruction, Operation, DecodeError, from_opcode};
+use crate::cartridge::{Cartridge, MemoryError, WatchHit};
+use crate::quirks::{Quirks, IndexIncrement, MemoryOverflow};
+use crate::snapshot::{SnapshotError, StateReader, StateWriter};
+use crate::random::{RandomSource, Xorshift};
 use std::fmt;
 use std::collections::VecDeque;
 
@@ -12,82 +12,228 @@ struct Position {
   y: u8
 }
 
-pub struct PixelEvent {
-  pub x: u8,
-  pub y: u8,
-  pub on: bool,
-  pub clear_all: bool
+pub enum PixelEvent {
+  Set { x: u8, y: u8, color: u8 }, // color is the bitmask of planes the pixel is set in
+  ClearAll,
+  Resize { width: u8, height: u8 }
 }
 
 pub struct TermDisplay {
-  display: [bool; 64*32],
+  display: Vec<u8>, // bitmask of the (XO-CHIP) planes each pixel is set in
+  hires: bool,
+  planes: u8,       // planes selected for drawing, clearing and scrolling
   pub flips: VecDeque<PixelEvent>
 }
 
 impl TermDisplay {
   pub const WIDTH_PX : u8 = 64;
   pub const HEIGHT_PX : u8 = 32;
+  pub const HIRES_WIDTH_PX : u8 = 128; // SUPER-CHIP high resolution mode
+  pub const HIRES_HEIGHT_PX : u8 = 64;
+  pub const DEFAULT_PALETTE : [u32; 4] = [0x1d1f26, 0xf0ffff, 0xff6600, 0x662200]; // 0xRRGGBB of background, plane 1, plane 2, both planes
 }
 
 impl TermDisplay {
   fn new() -> Self {
-    Self { display: [false; 64*32], flips: VecDeque::new() }
+    let size = Self::HIRES_WIDTH_PX as usize * Self::HIRES_HEIGHT_PX as usize;
+    Self { display: vec![0; size], hires: false, planes: 0x1, flips: VecDeque::new() }
+  }
+
+  pub fn width(&self) -> u8 {
+    if self.hires { Self::HIRES_WIDTH_PX } else { Self::WIDTH_PX }
+  }
+
+  pub fn height(&self) -> u8 {
+    if self.hires { Self::HIRES_HEIGHT_PX } else { Self::HEIGHT_PX }
   }
 
   fn clear(&mut self) {
-    self.display.fill( false );
+    let keep = !self.planes;
+    self.display.iter_mut().for_each(|px| *px &= keep);
+    self.redraw()
   }
 
-  fn get_idx(p: Position) -> usize {
-    let y_rollaround = (p.y & 0x1f) as usize; // mod 32 
-    let x_rollaround = (p.x & 0x3f) as usize; // mod 64
+  fn set_hires(&mut self, hires: bool) {
+    self.hires = hires;
+    self.display.fill( 0 );
+    self.flips.push_back(PixelEvent::Resize { width: self.width(), height: self.height() })
+  }
 
-    y_rollaround*(Self::WIDTH_PX as usize) + x_rollaround
+  fn get_idx(&self, p: Position) -> usize {
+    let y_rollaround = (p.y % self.height()) as usize;
+    let x_rollaround = (p.x % self.width()) as usize;
+
+    y_rollaround*(self.width() as usize) + x_rollaround
+  }
+
+  /// bitmask of the planes the pixel at x, y is set in
+  pub fn pixel(&self, x: u8, y: u8) -> u8 {
+    self.get_pixel(Position { x, y })
   }
 
-  fn get_pixel(&self, p: Position) -> bool {
-    let idx = Self::get_idx(p);
+  fn get_pixel(&self, p: Position) -> u8 {
+    let idx = self.get_idx(p);
     self.display[idx]   
   }
 
   fn get_character(&self, p: Position) -> &str {
-    if self.get_pixel(p) { "██" } else { "  " }
+    match self.get_pixel(p) { 0 => "  ", 1 => "██", 2 => "▒▒", _ => "▓▓" }
+  }
+
+  /// flips a pixel in `plane` and returns whether it was switched off
+  fn flip_pixel(&mut self, p: Position, plane: u8) -> bool {
+    let idx = self.get_idx(p);
+    let oldstate = self.display[idx];
+    self.display[idx] ^= plane;
+    let w = self.width() as usize;
+    self.flips.push_back(PixelEvent::Set { x: (idx % w) as u8, y: (idx / w) as u8, color: self.display[idx] });
+    oldstate & plane != 0
+  }
+
+  /// xors `rows` (msb is leftmost) of `bits` width onto `plane`, returns whether any pixel was switched off
+  fn draw_sprite(&mut self, x0: u8, y0: u8, rows: &[u16], bits: u8, plane: u8, clip: bool) -> bool {
+    let x0 = x0 % self.width();
+    let y0 = y0 % self.height();
+
+    let mut any_flipped_off = false;
+    for (p, row) in rows.iter().enumerate() {
+      let y = y0 as usize + p;
+      if clip && y >= self.height() as usize { break }
+
+      for q in 0..bits {
+        let x = x0 as usize + q as usize;
+        if clip && x >= self.width() as usize { break }
+
+        if (row >> (bits - 1 - q)) & 0x01 == 0x01 && self.flip_pixel(Position { x: x as u8, y: y as u8 }, plane) {
+          any_flipped_off = true;
+        }
+      }
+    }
+    any_flipped_off
+  }
+
+  /// redraws the whole display, e.g. after scrolling
+  fn redraw(&mut self) {
+    self.flips.push_back(PixelEvent::ClearAll);
+    for y in 0..self.height() {
+      for x in 0..self.width() {
+        let color = self.get_pixel(Position { x, y });
+        if color != 0 {
+          self.flips.push_back(PixelEvent::Set { x, y, color })
+        }
+      }
+    }
+  }
+
+  /// pixels are packed four to a byte, they only use two bits
+  fn save(&self, w: &mut StateWriter) {
+    w.bool(self.hires);
+    w.u8(self.planes);
+    let packed: Vec<u8> = self.display.chunks(4).map(|px| px.iter().enumerate().fold(0, |b, (n, p)| b | (p & 0x3) << (2*n))).collect();
+    w.bytes(&packed)
   }
 
-  fn set_pixel(&mut self, p: Position, tf: bool) {
-    let idx = Self::get_idx(p);
-    self.display[idx] = tf
+  fn restore(r: &mut StateReader) -> Result<Self, SnapshotError> {
+    let mut display = Self::new();
+    display.hires = r.bool()?;
+    display.planes = r.u8()? & 0x3;
+    let packed = r.bytes()?;
+    if 4*packed.len() != display.display.len() { return Err(SnapshotError::Invalid("display size")) }
+    for (idx, px) in display.display.iter_mut().enumerate() {
+      *px = (packed[idx / 4] >> (2*(idx % 4))) & 0x3
+    }
+    // the window may show another resolution and nothing of this display yet
+    display.flips.push_back(PixelEvent::Resize { width: display.width(), height: display.height() });
+    display.redraw();
+    Ok(display)
+  }
+
+  /// moves the selected planes by dx, dy pixels, pixels moving out of the display are lost
+  fn scroll(&mut self, dx: i16, dy: i16) {
+    let (w, h) = (self.width() as i16, self.height() as i16);
+    let old = self.display.clone();
+    for y in 0..h {
+      for x in 0..w {
+        let (sx, sy) = (x - dx, y - dy);
+        let moved = if sx >= 0 && sx < w && sy >= 0 && sy < h { old[(sy*w + sx) as usize] } else { 0 };
+        let idx = (y*w + x) as usize;
+        self.display[idx] = (old[idx] & !self.planes) | (moved & self.planes)
+      }
+    }
+    self.redraw()
   }
 }
 
 impl fmt::Display for TermDisplay {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    for y in 0..Self::HEIGHT_PX {
-      for x in 0..Self::WIDTH_PX {
-        write!(f, "{}", self.get_character(Position { x, y })).unwrap();
+    for y in 0..self.height() {
+      for x in 0..self.width() {
+        write!(f, "{}", self.get_character(Position { x, y }))?;
       }
-      write!(f, "\n").unwrap();
+      writeln!(f)?;
     }
-    write!(f, "\n")
+    writeln!(f)
   }
 
 }
 
+#[derive(Default)]
 pub struct HexKeyboard {
-  states: VecDeque<u8>, // to be consumed by instructions and produced by events
+  pressed: u16,         // bit k is set while key k is held down
+  awaiting: Option<u8>  // key pressed during FX0A, which completes once it is released
 }
 
 impl HexKeyboard {
   pub fn new() -> Self {
-    Self { states: VecDeque::new() }
+    Self { pressed: 0, awaiting: None }
+  }
+
+  pub fn set_key(&mut self, k: u8, down: bool) {
+    let bit = 1 << (k & 0xf);
+    if down { self.pressed |= bit } else { self.pressed &= !bit }
   }
 
-  pub fn push(&mut self, k: u8) {
-    self.states.push_back(k)
+  /// replaces the state of all 16 keys at once, bit k is key k
+  pub fn set_state(&mut self, pressed: u16) {
+    self.pressed = pressed
   }
 
-  pub fn consume(&mut self) -> u8 {
-    match self.states.pop_front() { Some(k) => k, None => 0u8 }
+  pub fn state(&self) -> u16 {
+    self.pressed
+  }
+
+  pub fn is_pressed(&self, k: u8) -> bool {
+    self.pressed & (1 << (k & 0xf)) != 0
+  }
+
+  fn save(&self, w: &mut StateWriter) {
+    w.u16(self.pressed);
+    w.u8(self.awaiting.unwrap_or(0xff))
+  }
+
+  fn restore(r: &mut StateReader) -> Result<Self, SnapshotError> {
+    let pressed = r.u16()?;
+    let awaiting = match r.u8()? {
+      0xff => None,
+      k if k < 16 => Some(k),
+      _ => return Err(SnapshotError::Invalid("awaited key"))
+    };
+    Ok(Self { pressed, awaiting })
+  }
+
+  /// polled by FX0A: like the VIP, a key counts once it has been pressed and released again
+  fn released_key(&mut self) -> Option<u8> {
+    match self.awaiting {
+      Some(k) if !self.is_pressed(k) => {
+        self.awaiting = None;
+        Some(k)
+      },
+      Some(_) => None,
+      None => {
+        self.awaiting = (0..16).find(|&k| self.is_pressed(k));
+        None
+      }
+    }
   }
 }
 
@@ -102,70 +248,160 @@ impl Register {
     Self { v: [0; 16], delay: 0, sound: 0 }
   }
 
-  pub fn get(&self, vs: Varset) -> u8 {
+  fn save(&self, w: &mut StateWriter) {
+    self.v.iter().for_each(|v| w.u8(*v));
+    w.u8(self.delay);
+    w.u8(self.sound)
+  }
+
+  fn restore(r: &mut StateReader) -> Result<Self, SnapshotError> {
+    Ok(Self { v: r.array()?, delay: r.u8()?, sound: r.u8()? })
+  }
+
+  pub fn get(&self, vs: Varset) -> Result<u8, FaultReason> {
     match vs {
-      Varset::V(vnum) => self.v[vnum as usize],
-      Varset::Keyboard => panic!("keyboard is not in register"),
-      Varset::DelayTimer => self.delay,
-      Varset::SoundTimer => self.sound
+      Varset::V(vnum) => Ok(self.v[vnum as usize]),
+      Varset::Keyboard => Err(FaultReason::InvalidOperand(vs)),
+      Varset::DelayTimer => Ok(self.delay),
+      Varset::SoundTimer => Ok(self.sound)
     }
   }
 
-  fn set(&mut self, vs: Varset, val: u8) {
+  fn set(&mut self, vs: Varset, val: u8) -> Result<(), FaultReason> {
     match vs {
       Varset::V(vnum) => self.v[vnum as usize] = val,
-      Varset::Keyboard => panic!("memory should not set keyboard"),
+      Varset::Keyboard => return Err(FaultReason::InvalidOperand(vs)),
       Varset::DelayTimer => self.delay = val,
       Varset::SoundTimer => self.sound = val
     }
+    Ok(())
   }
 
-  fn inc_nocarry(&mut self, vs: Varset, val: u8) {
-    let new_val = self.get(vs.clone()).wrapping_add(val);
+  fn inc_nocarry(&mut self, vs: Varset, val: u8) -> Result<(), FaultReason> {
+    let new_val = self.get(vs.clone())?.wrapping_add(val);
     self.set(vs, new_val)
   }
 
-  fn inc_withcarry(&mut self, vs: Varset, val: u8) {
-    let new_val = self.get(vs.clone()) as u16 + val as u16;
+  fn inc_withcarry(&mut self, vs: Varset, val: u8) -> Result<(), FaultReason> {
+    let new_val = self.get(vs.clone())? as u16 + val as u16;
     let carry = (new_val & 0xff00) > 0;
-    self.set(Varset::V(0xf),  if carry { 1 } else { 0 });
-    self.set(vs, (new_val & 0x00ff) as u8)
+    self.set(vs, (new_val & 0x00ff) as u8)?;
+    self.set(Varset::V(0xf),  if carry { 1 } else { 0 }) // after the result, the flag wins if vs is VF
+  }
+
+  fn set_to_var(&mut self, vs: Varset, vi: Varset) -> Result<(), FaultReason> {
+    self.set(vs, self.get(vi)?)
+  }
+
+  fn decrement_and_flip(&mut self, vs: Varset, vi: Varset) -> Result<(), FaultReason> {
+    let x = self.get(vs.clone())?;
+    let y = self.get(vi)?;
+    self.set(vs, y.wrapping_sub(x))?;
+    self.set(Varset::V(0xf), if y >= x { 1 } else { 0 }) // VF is set when there is no borrow
+  }
+
+  fn decrement_with_borrow(&mut self, vs: Varset, vi: Varset) -> Result<(), FaultReason> {
+    let x = self.get(vs.clone())?;
+    let y = self.get(vi)?;
+    self.set(vs, x.wrapping_sub(y))?;
+    self.set(Varset::V(0xf), if x >= y { 1 } else { 0 }) // VF is set when there is no borrow
+  }
+
+  fn bitshift_and_store(&mut self, vs: Varset, source: Varset) -> Result<(), FaultReason> {
+    let val = self.get(source)?;
+    self.set(vs, val >> 1)?;
+    self.set(Varset::V(0xf), val & 0x01)
   }
 
-  fn set_to_var(&mut self, vs: Varset, vi: Varset) {
-    self.set(vs, self.get(vi));
+  fn bitshift_left_and_store(&mut self, vs: Varset, source: Varset) -> Result<(), FaultReason> {
+    let val = self.get(source)?;
+    self.set(vs, val << 1)?;
+    self.set(Varset::V(0xf), val >> 7)
   }
 
-  fn decrement_and_flip(&mut self, vs: Varset, vi: Varset) {
-    let x = self.get(vs.clone());
-    let y = self.get(vi);
-    self.set(vs, y-x)
+  fn bitwise(&mut self, vs: Varset, vi: Varset, f: fn(u8, u8) -> u8, reset_vf: bool) -> Result<(), FaultReason> {
+    self.set(vs.clone(), f(self.get(vs)?, self.get(vi)?))?;
+    if reset_vf { self.set(Varset::V(0xf), 0)? }
+    Ok(())
   }
 
-  fn decrement_with_borrow(&mut self, vs: Varset, vi: Varset) {
-    let val = (self.get(vs.clone()) as u16).wrapping_sub( self.get(vi) as u16 );
-    let borrow = (val & 0xff00) > 0;
-    self.set(Varset::V(0xf),  if borrow { 1 } else { 0 });
-    self.set(vs, (val & 0x00ff) as u8)
+}
+
+/// what the cpu is doing after an instruction has been run
+#[derive(Debug,Clone,PartialEq)]
+pub enum StepOutcome {
+  Continue,
+  WaitingForKey,
+  WaitingForVblank,
+  Exit
+}
+
+/// why an instruction could not be run
+#[derive(Debug,Clone,PartialEq)]
+pub enum FaultReason {
+  Undecodable(DecodeError),
+  MachineCodeRoutine(u16),
+  StackUnderflow,
+  StackOverflow,
+  ProgramCounterOutOfRange,
+  MemoryOutOfRange(u32),
+  InvalidOperand(Varset)
+}
+
+impl fmt::Display for FaultReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FaultReason::Undecodable(e) => write!(f, "{}", e),
+      FaultReason::MachineCodeRoutine(routine) => write!(f, "machine code routine {:#05x} is not supported", routine),
+      FaultReason::StackUnderflow => write!(f, "return without subroutine call"),
+      FaultReason::StackOverflow => write!(f, "more than {} nested subroutine calls", Chip8State::STACK_DEPTH),
+      FaultReason::ProgramCounterOutOfRange => write!(f, "program counter left the memory"),
+      FaultReason::MemoryOutOfRange(address) => write!(f, "memory address {:#06x} is out of range", address),
+      FaultReason::InvalidOperand(vs) => write!(f, "{} is not a valid operand here", vs)
+    }
   }
+}
 
-  fn bitshift_and_store(&mut self, vs: Varset) {
-    let val = self.get(vs.clone());
-    self.set(Varset::V(0xf), val & 0x01);
-    self.set(vs, val >> 1)
+impl From<MemoryError> for FaultReason {
+  fn from(e: MemoryError) -> Self {
+    FaultReason::MemoryOutOfRange(e.address as u32)
   }
+}
 
+/// a fault raised while executing the instruction at `pc`
+#[derive(Debug,Clone,PartialEq)]
+pub struct ExecError {
+  pub pc: u16,
+  pub opcode: Option<u16>, // None when the instruction was not fetched from memory
+  pub reason: FaultReason
 }
 
+impl fmt::Display for ExecError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.opcode {
+      Some(opcode) => write!(f, "fault at pc:{:#06x} opcode:{:#06x}: {}", self.pc, opcode, self.reason),
+      None => write!(f, "fault at pc:{:#06x}: {}", self.pc, self.reason)
+    }
+  }
+}
+
+impl std::error::Error for ExecError {}
+
 pub struct Chip8State {
   pub pc: u16,      // main address register (program counter)
   i: u16,       // additional 16-bit address register
   stack: Vec<u16>, // stores registers when (possibly multiple enclosed) subroutines are called
   register: Register,
   pub keyboard: HexKeyboard,
-  pub display: TermDisplay, // bits of the 32x64 display. the u8s are xor'ed with sprites and thus form a part of the state
+  pub display: TermDisplay, // pixels of the 64x32 (or 128x64) display. they are xor'ed with sprites and thus form a part of the state
   pub cartridge: Cartridge,
-  rng: ThreadRng,  // custom: random number generator
+  pub quirks: Quirks,
+  rpl: [u8; 16],   // SUPER-CHIP persistent user flags (HP-48 RPL registers)
+  pub audio_pattern: Option<[u8; 16]>, // XO-CHIP 1-bit sample buffer, replaces the plain tone once loaded
+  pub pitch: u8,   // XO-CHIP playback rate of the sample buffer
+  vblank: bool,    // a 60 Hz interrupt has happened since the last sprite was drawn
+  rng: Box<dyn RandomSource>, // custom: random number generator
+  watch_hits: Vec<WatchHit>, // custom: watched memory accesses, tagged with the instruction that made them
 }
 
 impl fmt::Display for Chip8State {
@@ -176,139 +412,540 @@ impl fmt::Display for Chip8State {
 
 
 impl Chip8State {
-  pub fn new(cartridge: Cartridge) -> Self {
-      Self { pc: 0x200, i: 0, stack: Vec::with_capacity(32),
+  const STACK_DEPTH: usize = 16;
+
+  pub fn new(cartridge: Cartridge, quirks: Quirks) -> Self {
+      Self { pc: 0x200, i: 0, stack: Vec::with_capacity(Self::STACK_DEPTH),
         register: Register::new(), keyboard: HexKeyboard::new(), display: TermDisplay::new(),
-        cartridge, rng: rand::thread_rng() }
+        cartridge, quirks, rpl: [0; 16], audio_pattern: None, pitch: 64, vblank: false, rng: Box::new(Xorshift::new(rand::random())), watch_hits: Vec::new() }
   }
 
-  pub fn get(&mut self, vs: Varset) -> u8 {
-    match vs {
-      Varset::Keyboard => self.keyboard.consume(),
-      _ => self.register.get(vs)
+  /// replaces the random number generator, which is seeded from the system otherwise
+  pub fn set_random(&mut self, rng: Box<dyn RandomSource>) {
+    self.rng = rng
+  }
+
+  /// value of variable V0 -- VF
+  pub fn v(&self, vnum: u8) -> u8 {
+    self.register.v[(vnum & 0xf) as usize]
+  }
+
+  pub fn i(&self) -> u16 {
+    self.i
+  }
+
+  pub fn delay_timer(&self) -> u8 {
+    self.register.delay
+  }
+
+  pub fn sound_timer(&self) -> u8 {
+    self.register.sound
+  }
+
+  /// return addresses of the running subroutines, innermost last
+  pub fn stack(&self) -> &[u16] {
+    &self.stack
+  }
+
+  pub fn set_v(&mut self, vnum: u8, val: u8) {
+    self.register.v[(vnum & 0xf) as usize] = val
+  }
+
+  pub fn set_i(&mut self, i: u16) {
+    self.i = i
+  }
+
+  pub fn set_delay_timer(&mut self, val: u8) {
+    self.register.delay = val
+  }
+
+  pub fn set_sound_timer(&mut self, val: u8) {
+    self.register.sound = val
+  }
+
+  /// drops the innermost return addresses, or adds return addresses to 0x000
+  pub fn set_stack_depth(&mut self, depth: usize) -> Result<(), FaultReason> {
+    if depth > Self::STACK_DEPTH { return Err(FaultReason::StackOverflow) }
+    self.stack.resize(depth, 0);
+    Ok(())
+  }
+
+  /// all registers, timers and the stack in a single line, e.g. for fault reports
+  pub fn register_dump(&self) -> String {
+    let vs: Vec<String> = self.register.v.iter().enumerate().map(|(n, v)| format!("V{:1X}:{:02x}", n, v)).collect();
+    let stack: Vec<String> = self.stack.iter().map(|a| format!("{:#05x}", a)).collect();
+    format!("{} I:{:#06x} DT:{:02x} ST:{:02x} pc:{:#06x} stack:[{}]",
+      vs.join(" "), self.i, self.register.delay, self.register.sound, self.pc, stack.join(" "))
+  }
+
+  /// the complete machine state (not the quirks) as a versioned binary save state
+  pub fn save_state(&self) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.u16(self.pc);
+    w.u16(self.i);
+    w.u8(self.stack.len() as u8);
+    (0..Self::STACK_DEPTH).for_each(|n| w.u16(self.stack.get(n).copied().unwrap_or(0))); // fixed size, keeps deltas between states small
+    self.register.save(&mut w);
+    self.rpl.iter().for_each(|f| w.u8(*f));
+    w.bool(self.audio_pattern.is_some());
+    self.audio_pattern.unwrap_or_default().iter().for_each(|b| w.u8(*b));
+    w.u8(self.pitch);
+    w.bool(self.vblank);
+    w.u64(self.rng.state());
+    self.keyboard.save(&mut w);
+    self.display.save(&mut w);
+    self.cartridge.save(&mut w);
+    w.finish()
+  }
+
+  /// replaces the machine state with a save state, leaving it untouched if `bytes` cannot be restored
+  pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+    let mut r = StateReader::new(bytes)?;
+    let pc = r.u16()?;
+    let i = r.u16()?;
+    let depth = r.u8()? as usize;
+    if depth > Self::STACK_DEPTH { return Err(SnapshotError::Invalid("stack depth")) }
+    let mut stack = Vec::with_capacity(Self::STACK_DEPTH);
+    for _ in 0..Self::STACK_DEPTH {
+      stack.push(r.u16()?)
     }
+    stack.truncate(depth);
+    let register = Register::restore(&mut r)?;
+    let rpl = r.array()?;
+    let has_pattern = r.bool()?;
+    let pattern = r.array()?;
+    let pitch = r.u8()?;
+    let vblank = r.bool()?;
+    let rng_state = r.u64()?;
+    let keyboard = HexKeyboard::restore(&mut r)?;
+    let display = TermDisplay::restore(&mut r)?;
+    let mut cartridge = Cartridge::restore(&mut r)?;
+    r.finish()?;
+    cartridge.watchpoints = std::mem::take(&mut self.cartridge.watchpoints); // they belong to the session, not the save state
+
+    self.pc = pc;
+    self.i = i;
+    self.stack = stack;
+    self.register = register;
+    self.keyboard = keyboard;
+    self.display = display;
+    self.cartridge = cartridge;
+    self.rpl = rpl;
+    self.audio_pattern = if has_pattern { Some(pattern) } else { None };
+    self.pitch = pitch;
+    self.vblank = vblank;
+    self.rng.set_state(rng_state); // the generator stays, only its state comes from the save state
+    Ok(())
   }
 
   pub fn tick(&mut self) {
-    let delay = self.register.get(Varset::DelayTimer);
-    if delay > 0 { self.register.set(Varset::DelayTimer, delay-1) }
+    if self.register.delay > 0 { self.register.delay -= 1 }
+    if self.register.sound > 0 { self.register.sound -= 1 }
+    self.vblank = true;
+  }
+
+  /// the beeper sounds as long as the sound timer is non-zero
+  pub fn is_sounding(&self) -> bool {
+    self.register.sound > 0
+  }
+
+  fn var_equals_val(&self, vs: Varset, val: u8) -> Result<bool, FaultReason> {
+    Ok(self.register.get(vs)? == val)
+  }
+
+  /// comparing a variable with the keyboard tests whether the key it holds is pressed
+  fn vars_are_equal(&self, va: Varset, vb: Varset) -> Result<bool, FaultReason> {
+    match vb {
+      Varset::Keyboard => Ok(self.keyboard.is_pressed(self.register.get(va)?)),
+      _ => Ok(self.register.get(va)? == self.register.get(vb)?)
+    }
+  }
+
+  fn increment_i_after_load_store(&mut self, vmax: u8) {
+    self.i = self.i.wrapping_add(match self.quirks.load_store_index {
+      IndexIncrement::Unchanged => 0,
+      IndexIncrement::ByX => vmax as u16,
+      IndexIncrement::ByXPlusOne => vmax as u16 + 1
+    })
+  }
+
+  /// address I+offset, wrapped around or rejected past the end of memory depending on the quirks
+  fn address_from_i(&self, offset: u16) -> Result<u16, FaultReason> {
+    let address = self.i as usize + offset as usize;
+    let size = self.cartridge.memory.len();
+    match self.quirks.memory_overflow {
+      _ if address < size => Ok(address as u16),
+      MemoryOverflow::Wrap => Ok((address % size) as u16),
+      MemoryOverflow::Fault => Err(FaultReason::MemoryOutOfRange(address as u32))
+    }
+  }
+
+  fn read_i(&self, offset: u16) -> Result<u8, FaultReason> {
+    Ok(self.cartridge.get_memory(self.address_from_i(offset)?)?)
+  }
+
+  fn write_i(&mut self, offset: u16, val: u8) -> Result<(), FaultReason> {
+    Ok(self.cartridge.set_memory(self.address_from_i(offset)?, val)?)
+  }
+
+  /// skips the next instruction, which is 4 bytes long if it is the XO-CHIP long I load
+  fn skip_next(&mut self) {
+    let long = self.cartridge.get_opcode_from(self.pc).map(|op| op == 0xf000).unwrap_or(false);
+    self.pc = self.pc.wrapping_add(if long { 4 } else { 2 })
+  }
+
+  /// register numbers from va to vb, in descending order if vb < va
+  fn var_range(va: Varset, vb: Varset) -> Result<Vec<u8>, FaultReason> {
+    match (va, vb) {
+      (Varset::V(x), Varset::V(y)) if x <= y => Ok((x..=y).collect()),
+      (Varset::V(x), Varset::V(y)) => Ok((y..=x).rev().collect()),
+      (va, _) => Err(FaultReason::InvalidOperand(va))
+    }
   }
 
-  fn var_equals_val(&mut self, vs: Varset, val: u8) -> bool {
-    self.get(vs) == val
+  /// fetch, decode and run the instruction at the program counter
+  pub fn step(&mut self) -> Result<StepOutcome, ExecError> {
+    self.cartridge.watch_execute(self.pc);
+    let opcode = self.cartridge.get_opcode_from(self.pc)
+      .map_err(|_| ExecError { pc: self.pc, opcode: None, reason: FaultReason::ProgramCounterOutOfRange })?;
+    let instruction = from_opcode(opcode)
+      .map_err(|e| ExecError { pc: self.pc, opcode: Some(opcode), reason: FaultReason::Undecodable(e) })?;
+    self.run(instruction, Some(opcode))
   }
 
-  fn vars_are_equal(&mut self, va: Varset, vb: Varset) -> bool {
-    self.get(va) == self.get(vb)
-  } 
+  /// run an instruction that was not fetched from memory, as if it were at the program counter
+  pub fn run_instruction(&mut self, instruction: Instruction) -> Result<StepOutcome, ExecError> {
+    self.run(instruction, None)
+  }
+
+  /// `opcode` is what the instruction was decoded from, reported with its fault
+  fn run(&mut self, instruction: Instruction, opcode: Option<u16>) -> Result<StepOutcome, ExecError> {
+    let pc = self.pc;
+    let outcome = self.execute(instruction).map_err(|reason| {
+      self.pc = pc; // stay on the faulting instruction
+      ExecError { pc, opcode, reason }
+    });
+    if !self.cartridge.watchpoints().is_empty() {
+      let hits = self.cartridge.take_hits();
+      self.watch_hits.extend(hits.into_iter().map(|hit| WatchHit { pc, ..hit }));
+    }
+    outcome
+  }
+
+  /// the watched memory accesses since the last call
+  pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+    std::mem::take(&mut self.watch_hits)
+  }
 
-  pub fn run_instruction(&mut self, instruction: Instruction) {
-    self.pc += 2;
+  fn execute(&mut self, instruction: Instruction) -> Result<StepOutcome, FaultReason> {
+    self.pc = self.pc.wrapping_add(2);
     match instruction {
-      Instruction::RCARoutine(_r) => panic!("RCA routines are not implemented"),
+      Instruction::RCARoutine(r) => return Err(FaultReason::MachineCodeRoutine(r)),
       
       Instruction::GotoAdress(address) => self.pc = address,
-      Instruction::RunSubroutineAtAdress(address) => {self.stack.push(self.pc); self.pc = address},
-      Instruction::ReturnFromSubroutine => self.pc = self.stack.pop().unwrap(),
+      Instruction::GotoAdressPlusV0(address) => {
+        let offset = if self.quirks.jump_uses_vx { Varset::V((address >> 8) as u8) } else { Varset::V(0) };
+        self.pc = address + self.register.get(offset)? as u16
+      },
+      Instruction::RunSubroutineAtAdress(address) => {
+        if self.stack.len() >= Self::STACK_DEPTH { return Err(FaultReason::StackOverflow) }
+        self.stack.push(self.pc);
+        self.pc = address
+      },
+      Instruction::ReturnFromSubroutine => self.pc = self.stack.pop().ok_or(FaultReason::StackUnderflow)?,
       
-      Instruction::SkipNextIfVarEq(vs, val) => if self.var_equals_val(vs, val) { self.pc += 2 },
-      Instruction::SkipNextIfVarNeq(vs, val) => if !self.var_equals_val(vs, val) { self.pc += 2 },
-      Instruction::SkipNextIfVarsEq(va, vb) => if self.vars_are_equal(va, vb) { self.pc += 2 },
-      Instruction::SkipNextIfVarsNeq(va, vb) => if !self.vars_are_equal(va, vb) { self.pc += 2 },
+      Instruction::SkipNextIfVarEq(vs, val) => if self.var_equals_val(vs, val)? { self.skip_next() },
+      Instruction::SkipNextIfVarNeq(vs, val) => if !self.var_equals_val(vs, val)? { self.skip_next() },
+      Instruction::SkipNextIfVarsEq(va, vb) => if self.vars_are_equal(va, vb)? { self.skip_next() },
+      Instruction::SkipNextIfVarsNeq(va, vb) => if !self.vars_are_equal(va, vb)? { self.skip_next() },
 
       Instruction::VariableOnValue(vs, val, op) => match op {
-        Operation::Set => self.register.set(vs, val),
-        Operation::IncrementNoCarry => self.register.inc_nocarry(vs, val),
+        Operation::Set => self.register.set(vs, val)?,
+        Operation::IncrementNoCarry => self.register.inc_nocarry(vs, val)?,
         Operation::Randomize => {
-          let random_number: u8 = self.rng.gen();
-          self.register.set(vs, random_number & val)
+          let random_number = self.rng.next_u8();
+          self.register.set(vs, random_number & val)?
         },
-        _ => panic!("operation not implemented")
+        _ => return Err(FaultReason::InvalidOperand(vs))
       },
       Instruction::VariableOnVariable(vs, vi, op) => match op {
-        Operation::Set => self.register.set_to_var(vs, vi),
-        Operation::IncrementNoCarry => self.register.inc_nocarry(vs, self.register.get(vi)),
-        Operation::IncrementWithCarry => self.register.inc_withcarry(vs, self.register.get(vi)),
-        Operation::DecrementAndFlip => self.register.decrement_and_flip(vs, vi),
-        Operation::DecrementWithBorrow => self.register.decrement_with_borrow(vs, vi),
-        Operation::BitOr => self.register.set(vs.clone(), self.register.get(vs) | self.register.get(vi)),
-        Operation::BitAnd => self.register.set(vs.clone(), self.register.get(vs) & self.register.get(vi)),
-        Operation::BitXor => self.register.set(vs.clone(), self.register.get(vs) ^ self.register.get(vi)),
-        Operation::BitshiftAndStore => self.register.bitshift_and_store(vs),
-        _ => panic!("{:?} not implemented", op)
+        Operation::Set => self.register.set_to_var(vs, vi)?,
+        Operation::IncrementNoCarry => self.register.inc_nocarry(vs, self.register.get(vi)?)?,
+        Operation::IncrementWithCarry => self.register.inc_withcarry(vs, self.register.get(vi)?)?,
+        Operation::DecrementAndFlip => self.register.decrement_and_flip(vs, vi)?,
+        Operation::DecrementWithBorrow => self.register.decrement_with_borrow(vs, vi)?,
+        Operation::BitOr => self.register.bitwise(vs, vi, |x, y| x | y, self.quirks.logic_resets_vf)?,
+        Operation::BitAnd => self.register.bitwise(vs, vi, |x, y| x & y, self.quirks.logic_resets_vf)?,
+        Operation::BitXor => self.register.bitwise(vs, vi, |x, y| x ^ y, self.quirks.logic_resets_vf)?,
+        Operation::BitshiftAndStore => {
+          let source = if self.quirks.shift_uses_vy { vi } else { vs.clone() };
+          self.register.bitshift_and_store(vs, source)?
+        },
+        Operation::BitshiftLeftAndStore => {
+          let source = if self.quirks.shift_uses_vy { vi } else { vs.clone() };
+          self.register.bitshift_left_and_store(vs, source)?
+        },
+        _ => return Err(FaultReason::InvalidOperand(vs))
       },
 
 
       Instruction::SetITo(num) => self.i = num,
+      Instruction::SetIToNextWord => {
+        self.i = self.cartridge.get_opcode_from(self.pc).map_err(|_| FaultReason::ProgramCounterOutOfRange)?;
+        self.pc = self.pc.wrapping_add(2)
+      },
       Instruction::IOnVariable(vs, op) => match op {
-        Operation::Set => self.i = self.register.get(vs) as u16,
-        Operation::IncrementNoCarry => self.i += self.register.get(vs) as u16,
-        Operation::SpriteMultiply => self.i = 5*(self.register.get(vs) as u16),
-        _ => panic!("{:?} not implemented", op)
+        Operation::Set => self.i = self.register.get(vs)? as u16,
+        Operation::IncrementNoCarry => self.i = self.i.wrapping_add(self.register.get(vs)? as u16),
+        Operation::SpriteMultiply => self.i = self.cartridge.font_address(self.register.get(vs)?),
+        Operation::BigSpriteMultiply => self.i = self.cartridge.big_font_address(self.register.get(vs)?),
+        _ => return Err(FaultReason::InvalidOperand(vs))
       },
       Instruction::StoreVarAsDecimalInPositionI(vs) => {
-        let val = self.register.get(vs);
+        let val = self.register.get(vs)?;
         let hun = val / 100;
-        self.cartridge.set_memory(self.i, hun);
+        self.write_i(0, hun)?;
         let dec = val / 10 - hun*10;
-        self.cartridge.set_memory(self.i+1, dec);
+        self.write_i(1, dec)?;
         let uno = val - dec*10 - hun*100;
-        self.cartridge.set_memory(self.i+2, uno);
+        self.write_i(2, uno)?;
       },
       Instruction::DumpVariablesUptoInPositionI(vs) => {
         match vs {
           Varset::V(vmax) => {
             for vnum in 0..=vmax {
-              self.cartridge.set_memory(self.i + vnum as u16, self.register.get(Varset::V(vnum)))
+              self.write_i(vnum as u16, self.register.get(Varset::V(vnum))?)?
             }
+            self.increment_i_after_load_store(vmax)
           },
-          _ => panic!("dump requires a V* variable as input")
+          _ => return Err(FaultReason::InvalidOperand(vs))
         }
       },
       Instruction::LoadVariablesUptoFromPositionI(vs) => {
         match vs {
           Varset::V(vmax) => {
             for vnum in 0..=vmax {
-              self.register.set(Varset::V(vnum), self.cartridge.get_memory(self.i + vnum as u16))
+              self.register.set(Varset::V(vnum), self.read_i(vnum as u16)?)?
             }
+            self.increment_i_after_load_store(vmax)
           },
-          _ => panic!("dump requires a V* variable as input")
+          _ => return Err(FaultReason::InvalidOperand(vs))
+        }
+      },
+      Instruction::DumpVariablesRangeInPositionI(va, vb) => {
+        for (offset, vnum) in Self::var_range(va, vb)?.into_iter().enumerate() {
+          self.write_i(offset as u16, self.register.get(Varset::V(vnum))?)?
+        }
+      },
+      Instruction::LoadVariablesRangeFromPositionI(va, vb) => {
+        for (offset, vnum) in Self::var_range(va, vb)?.into_iter().enumerate() {
+          self.register.set(Varset::V(vnum), self.read_i(offset as u16)?)?
+        }
+      },
+      Instruction::LoadAudioPattern => {
+        let mut pattern = [0; 16];
+        for (offset, byte) in pattern.iter_mut().enumerate() {
+          *byte = self.read_i(offset as u16)?
+        }
+        self.audio_pattern = Some(pattern)
+      },
+      Instruction::SetPitchTo(vs) => self.pitch = self.register.get(vs)?,
+      Instruction::StoreFlagsUpto(vs) => match vs {
+        Varset::V(vmax) => self.rpl[..=vmax as usize].copy_from_slice(&self.register.v[..=vmax as usize]),
+        _ => return Err(FaultReason::InvalidOperand(vs))
+      },
+      Instruction::LoadFlagsUpto(vs) => match vs {
+        Varset::V(vmax) => self.register.v[..=vmax as usize].copy_from_slice(&self.rpl[..=vmax as usize]),
+        _ => return Err(FaultReason::InvalidOperand(vs))
+      },
+      Instruction::WaitForKeypressInto(vs) => match self.keyboard.released_key() {
+        Some(k) => self.register.set(vs, k)?,
+        None => {
+          self.pc = self.pc.wrapping_sub(2); // no key yet: execute this instruction again
+          return Ok(StepOutcome::WaitingForKey)
         }
       },
 
-      Instruction::ClearDraw => {self.display.clear(); self.display.flips.push_back(PixelEvent { x: 0, y: 0, on: false, clear_all: true }) },
+      Instruction::ClearDraw => self.display.clear(),
+      Instruction::SetHighResolution(hires) => self.display.set_hires(hires),
+      Instruction::ScrollDown(n) => self.display.scroll(0, n as i16),
+      Instruction::ScrollUp(n) => self.display.scroll(0, -(n as i16)),
+      Instruction::SelectPlanes(planes) => self.display.planes = planes,
+      Instruction::ScrollRight => self.display.scroll(4, 0),
+      Instruction::ScrollLeft => self.display.scroll(-4, 0),
+      Instruction::ExitInterpreter => return Ok(StepOutcome::Exit),
       Instruction::DrawSpriteXYH(vx, vy, h) => {
-        let x0 = self.register.get(vx) + 7;
-        let y0 = self.register.get(vy);
-        
-        let mut any_flipped_off = false;
-        for p in 0..h {
-          let bitti = self.cartridge.get_memory(self.i + p as u16);
-          let y = y0+p;
-
-          for q in 0..8 {
-            let x = x0-q;
-
-            let flip = (bitti >> q) & 0x01 == 0x01;
-
-            if flip {
-              //ToDo: display.flip_pixel(x,y) -> any_flipped_off
-              let p = Position{x,y};
-              let oldstate = self.display.get_pixel(p.clone());
-
-              self.display.flips.push_back(PixelEvent { x, y, on: !oldstate, clear_all: false });
+        if self.quirks.display_wait {
+          if !self.vblank {
+            self.pc = self.pc.wrapping_sub(2); // draw again after the next interrupt
+            return Ok(StepOutcome::WaitingForVblank)
+          }
+          self.vblank = false
+        }
+        let x0 = self.register.get(vx)?;
+        let y0 = self.register.get(vy)?;
 
-              self.display.set_pixel(p, !oldstate);
-              if oldstate {
-                  any_flipped_off = true;
-              }
+        let mut any_flipped_off = false;
+        let mut offset = 0;
+        for plane in [0x1, 0x2] {
+          if self.display.planes & plane == 0 { continue }
+          // each selected XO-CHIP plane consumes its own sprite data, one after the other
+          let (rows, bits) = if h == 0 && (self.display.hires || !self.quirks.lores_dxy0_8x16) {
+            let mut rows = Vec::with_capacity(16); // SUPER-CHIP 16x16 sprite
+            for _ in 0..16 {
+              let hi = self.read_i(offset)?;
+              let lo = self.read_i(offset + 1)?;
+              rows.push(u16::from_be_bytes([hi, lo]));
+              offset += 2
+            }
+            (rows, 16)
+          } else {
+            let h = if h == 0 { 16 } else { h }; // SUPER-CHIP 1.1 draws 8x16 in low resolution
+            let mut rows = Vec::with_capacity(h as usize);
+            for _ in 0..h {
+              rows.push(self.read_i(offset)? as u16);
+              offset += 1
             }
+            (rows, 8)
+          };
+
+          if self.display.draw_sprite(x0, y0, &rows, bits, plane, self.quirks.clip_sprites) {
+            any_flipped_off = true
           }
         }
-        self.register.set(Varset::V(0xf), if any_flipped_off { 1 } else { 0 });
+        self.register.set(Varset::V(0xf), if any_flipped_off { 1 } else { 0 })?;
       }
     }
+    Ok(StepOutcome::Continue)
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn register(vx: u8, vy: u8) -> Register {
+    let mut r = Register::new();
+    r.v[0] = vx;
+    r.v[1] = vy;
+    r
+  }
+
+  const X: Varset = Varset::V(0);
+  const Y: Varset = Varset::V(1);
+  const F: Varset = Varset::V(0xf);
+
+  #[test]
+  fn set_and_set_to_var() {
+    let mut r = register(0, 0x42);
+    r.set(X, 7).unwrap();
+    assert_eq!(r.get(X), Ok(7));
+    r.set_to_var(X, Y).unwrap();
+    assert_eq!(r.get(X), Ok(0x42));
+    r.set(Varset::DelayTimer, 9).unwrap();
+    assert_eq!(r.delay, 9);
+  }
+
+  #[test]
+  fn keyboard_is_no_register() {
+    let mut r = register(0, 0);
+    assert_eq!(r.get(Varset::Keyboard), Err(FaultReason::InvalidOperand(Varset::Keyboard)));
+    assert_eq!(r.set(Varset::Keyboard, 1), Err(FaultReason::InvalidOperand(Varset::Keyboard)));
+  }
+
+  #[test]
+  fn inc_nocarry_wraps_and_leaves_vf() {
+    let mut r = register(0xff, 0);
+    r.v[0xf] = 5;
+    r.inc_nocarry(X, 2).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(1), Ok(5)));
+  }
+
+  #[test]
+  fn inc_withcarry_sets_vf_on_overflow() {
+    let mut r = register(0xff, 0);
+    r.inc_withcarry(X, 1).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0), Ok(1)));
+    r.inc_withcarry(X, 0x10).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0x10), Ok(0)));
+  }
+
+  #[test]
+  fn inc_withcarry_into_vf_keeps_the_flag() {
+    let mut r = register(0, 0);
+    r.v[0xf] = 0xff;
+    r.inc_withcarry(F, 1).unwrap();
+    assert_eq!(r.get(F), Ok(1));
+  }
+
+  #[test]
+  fn decrement_with_borrow_sets_vf_without_borrow() {
+    let mut r = register(5, 3);
+    r.decrement_with_borrow(X, Y).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(2), Ok(1)));
+    let mut r = register(3, 5);
+    r.decrement_with_borrow(X, Y).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0xfe), Ok(0)));
+    let mut r = register(4, 4);
+    r.decrement_with_borrow(X, Y).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0), Ok(1)));
+  }
+
+  #[test]
+  fn subtraction_into_vf_keeps_the_flag() {
+    let mut r = register(0, 3);
+    r.v[0xf] = 5;
+    r.decrement_with_borrow(F, Y).unwrap();
+    assert_eq!(r.get(F), Ok(1));
+    r.v[0xf] = 5;
+    r.decrement_and_flip(F, Y).unwrap();
+    assert_eq!(r.get(F), Ok(0));
+  }
 
+  #[test]
+  fn decrement_and_flip_does_not_panic_on_underflow() {
+    let mut r = register(5, 3);
+    r.decrement_and_flip(X, Y).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0xfe), Ok(0)));
+    let mut r = register(3, 5);
+    r.decrement_and_flip(X, Y).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(2), Ok(1)));
+  }
+
+  #[test]
+  fn bitshifts_move_the_lost_bit_into_vf() {
+    let mut r = register(0x81, 0);
+    r.bitshift_and_store(X, X).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0x40), Ok(1)));
+    let mut r = register(0x81, 0);
+    r.bitshift_left_and_store(X, X).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0x02), Ok(1)));
+    let mut r = register(0, 0x02);
+    r.bitshift_and_store(X, Y).unwrap();
+    assert_eq!((r.get(X), r.get(Y), r.get(F)), (Ok(0x01), Ok(0x02), Ok(0)));
+  }
+
+  #[test]
+  fn bitwise_resets_vf_only_when_asked() {
+    let mut r = register(0b1100, 0b1010);
+    r.v[0xf] = 7;
+    r.bitwise(X, Y, |x, y| x | y, false).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0b1110), Ok(7)));
+    r.bitwise(X, Y, |x, y| x & y, true).unwrap();
+    assert_eq!((r.get(X), r.get(F)), (Ok(0b1010), Ok(0)));
+    r.bitwise(X, Y, |x, y| x ^ y, true).unwrap();
+    assert_eq!(r.get(X), Ok(0));
+  }
+
+  #[test]
+  fn fx0a_waits_for_press_and_release() {
+    let mut k = HexKeyboard::new();
+    assert_eq!(k.released_key(), None);
+    k.set_key(7, true);
+    assert_eq!(k.released_key(), None);
+    assert_eq!(k.released_key(), None);
+    k.set_key(7, false);
+    assert_eq!(k.released_key(), Some(7));
+    assert_eq!(k.released_key(), None);
+  }
+}
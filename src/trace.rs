@@ -0,0 +1,237 @@
+use std::fmt::Write;
+
+use crate::instruction::from_opcode;
+use crate::state::Chip8State;
+
+/// how `trace` writes the executed instructions
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TraceFormat {
+    Text,  // the display and a line per instruction, for reading
+    Jsonl, // a JSON object per instruction
+    Csv    // a header, then a row per instruction
+}
+
+impl TraceFormat {
+    pub const NAMES: [&'static str; 3] = ["text", "jsonl", "csv"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "jsonl" | "json" => Some(TraceFormat::Jsonl),
+            "csv" => Some(TraceFormat::Csv),
+            _ => None
+        }
+    }
+}
+
+/// an executed instruction with the registers after it ran and the memory it wrote
+#[derive(Debug,Clone,PartialEq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub frame: u32,
+    pub pc: u16,
+    pub opcode: u16,
+    pub instruction: String,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+    pub writes: Vec<(u16, u8)>
+}
+
+impl TraceRecord {
+    pub const CSV_HEADER: &'static str = "cycle,frame,pc,opcode,instruction,v0,v1,v2,v3,v4,v5,v6,v7,v8,v9,va,vb,vc,vd,ve,vf,i,sp,dt,st,writes";
+
+    /// the instruction `opcode` that was fetched from `pc`, `cas` has just run it
+    pub fn new(cycle: u64, frame: u32, pc: u16, opcode: u16, cas: &Chip8State, writes: Vec<(u16, u8)>) -> Self {
+        let instruction = match from_opcode(opcode) {
+            Ok(instr) => instr.to_string(),
+            Err(e) => e.to_string()
+        };
+        let mut v = [0; 16];
+        (0..16).for_each(|n| v[n as usize] = cas.v(n));
+        Self { cycle, frame, pc, opcode, instruction, v, i: cas.i(), sp: cas.stack().len() as u8,
+            dt: cas.delay_timer(), st: cas.sound_timer(), writes }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = format!("{{\"cycle\":{},\"frame\":{},\"pc\":{},\"opcode\":{},\"instruction\":\"{}\"",
+            self.cycle, self.frame, self.pc, self.opcode, self.instruction.replace('\\', "\\\\").replace('"', "\\\""));
+        self.v.iter().enumerate().for_each(|(n, v)| { let _ = write!(out, ",\"v{:x}\":{}", n, v); });
+        let _ = write!(out, ",\"i\":{},\"sp\":{},\"dt\":{},\"st\":{},\"writes\":[", self.i, self.sp, self.dt, self.st);
+        let writes: Vec<String> = self.writes.iter().map(|(address, val)| format!("[{},{}]", address, val)).collect();
+        out.push_str(&writes.join(","));
+        out.push_str("]}");
+        out
+    }
+
+    /// a row below `CSV_HEADER`, addresses and values in 0x hex and writes as `ADDR=VAL` separated by `;`
+    pub fn to_csv(&self) -> String {
+        let mut out = format!("{},{},{:#06x},{:#06x},\"{}\"", self.cycle, self.frame, self.pc, self.opcode, self.instruction.replace('"', "\"\""));
+        self.v.iter().for_each(|v| { let _ = write!(out, ",{:#04x}", v); });
+        let writes: Vec<String> = self.writes.iter().map(|(address, val)| format!("{:#06x}={:#04x}", address, val)).collect();
+        let _ = write!(out, ",{:#06x},{},{:#04x},{:#04x},{}", self.i, self.sp, self.dt, self.st, writes.join(";"));
+        out
+    }
+}
+
+/// the first record at which two traces differ
+#[derive(Debug,Clone,PartialEq)]
+pub enum Divergence {
+    /// fields that both traces have, with the value in the first and the second trace
+    Fields { record: usize, differences: Vec<(String, String, String)> },
+    /// one trace goes on after the other one has ended
+    Length { record: usize, first_is_longer: bool }
+}
+
+/// compares two traces record by record, only the fields that both of them have;
+/// either one may be JSON Lines or CSV, e.g. a log of another emulator
+pub fn diff(first: &str, second: &str) -> Result<Option<Divergence>, String> {
+    let first = parse(first).map_err(|e| format!("first trace: {}", e))?;
+    let second = parse(second).map_err(|e| format!("second trace: {}", e))?;
+    for (record, (a, b)) in first.iter().zip(&second).enumerate() {
+        let differences: Vec<(String, String, String)> = a.iter()
+            .filter_map(|(name, x)| b.iter().find(|(n, _)| n == name).map(|(_, y)| (name, x, y)))
+            .filter(|(name, x, y)| normalize(name, x) != normalize(name, y))
+            .map(|(name, x, y)| (name.clone(), x.clone(), y.clone()))
+            .collect();
+        if !differences.is_empty() {
+            return Ok(Some(Divergence::Fields { record, differences }))
+        }
+    }
+    Ok(match first.len().cmp(&second.len()) {
+        std::cmp::Ordering::Equal => None,
+        ordering => Some(Divergence::Length { record: first.len().min(second.len()), first_is_longer: ordering.is_gt() })
+    })
+}
+
+type Record = Vec<(String, String)>;
+
+fn parse(text: &str) -> Result<Vec<Record>, String> {
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+    match lines.clone().next() {
+        Some((_, l)) if l.trim_start().starts_with('{') => lines
+            .map(|(n, l)| parse_json(l).ok_or(format!("line {} is not a flat JSON object", n + 1)))
+            .collect(),
+        Some((_, header)) => {
+            lines.next();
+            let names = split_csv(header);
+            lines.map(|(n, l)| {
+                let row = split_csv(l);
+                if row.len() != names.len() { return Err(format!("line {} has {} columns, the header {}", n + 1, row.len(), names.len())) }
+                Ok(names.iter().cloned().zip(row).collect())
+            }).collect()
+        },
+        None => Ok(Vec::new())
+    }
+}
+
+/// the keys and raw values of a JSON object whose values are numbers, strings or arrays
+fn parse_json(line: &str) -> Option<Record> {
+    let mut rest = line.trim().strip_prefix('{')?.strip_suffix('}')?.trim();
+    let mut fields = Vec::new();
+    while !rest.is_empty() {
+        let (key, tail) = json_string(rest)?;
+        rest = tail.trim_start().strip_prefix(':')?.trim_start();
+        let (value, tail) = match rest.chars().next()? {
+            '"' => json_string(rest)?,
+            '[' => {
+                let mut depth = 0;
+                let end = rest.char_indices().find(|(_, c)| {
+                    match c { '[' => depth += 1, ']' => depth -= 1, _ => {} }
+                    depth == 0
+                })?.0;
+                (rest[..=end].chars().filter(|c| !c.is_whitespace()).collect(), &rest[end + 1..])
+            },
+            _ => {
+                let end = rest.find(',').unwrap_or(rest.len());
+                (rest[..end].trim().to_string(), &rest[end..])
+            }
+        };
+        fields.push((key, value));
+        rest = tail.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Some(fields)
+}
+
+/// a string at the start of `text` without its quotes, and what follows it
+fn json_string(text: &str) -> Option<(String, &str)> {
+    let mut out = String::new();
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    while let Some((n, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &text[n + 2..])),
+            '\\' => out.push(chars.next()?.1),
+            c => out.push(c)
+        }
+    }
+    None
+}
+
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.trim_end().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => { chars.next(); fields.last_mut().unwrap().push('"') },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c)
+        }
+    }
+    fields
+}
+
+/// numbers compare by value, whether written in decimal or as 0x hex, and memory writes whether
+/// written as a JSON array of pairs or as `ADDR=VAL;...`
+fn normalize(name: &str, value: &str) -> String {
+    if name == "writes" {
+        let pairs: Vec<String> = value.split(|c: char| "[],;=".contains(c)).filter(|n| !n.is_empty()).map(|n| normalize("", n)).collect();
+        return pairs.join(",")
+    }
+    match value.strip_prefix("0x").map(|hex| u64::from_str_radix(hex, 16)).unwrap_or_else(|| value.parse()) {
+        Ok(n) => n.to_string(),
+        Err(_) => value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cycle: u64, v0: u8) -> TraceRecord {
+        let mut v = [0; 16];
+        v[0] = v0;
+        TraceRecord { cycle, frame: 0, pc: 0x200 + 2*cycle as u16, opcode: 0x7001, instruction: "increment V0 by 0x01 \"ignoring\" carry".to_string(),
+            v, i: 0x300, sp: 0, dt: 0, st: 0, writes: vec![(0x300, v0)] }
+    }
+
+    #[test]
+    fn finds_the_first_divergence_across_formats() {
+        let jsonl: Vec<String> = (0..4).map(|n| record(n, n as u8).to_json()).collect();
+        let mut csv = vec![TraceRecord::CSV_HEADER.to_string()];
+        csv.extend((0..4).map(|n| record(n, n as u8).to_csv()));
+        assert_eq!(diff(&jsonl.join("\n"), &jsonl[..3].join("\n")), Ok(Some(Divergence::Length { record: 3, first_is_longer: true })));
+        // hex CSV columns compare with decimal JSON values
+        assert_eq!(diff(&jsonl.join("\n"), &csv.join("\n")), Ok(None));
+        let other: Vec<String> = (0..4).map(|n| record(n, if n < 2 { n as u8 } else { 9 }).to_json()).collect();
+        match diff(&jsonl.join("\n"), &other.join("\n")) {
+            Ok(Some(Divergence::Fields { record: 2, differences })) => {
+                let names: Vec<&str> = differences.iter().map(|(name, _, _)| name.as_str()).collect();
+                assert_eq!(names, ["v0", "writes"]);
+            },
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn parses_its_own_records() {
+        let fields = parse_json(&record(1, 5).to_json()).unwrap();
+        assert_eq!(fields[4], ("instruction".to_string(), "increment V0 by 0x01 \"ignoring\" carry".to_string()));
+        assert_eq!(fields.last().unwrap(), &("writes".to_string(), "[[768,5]]".to_string()));
+        assert_eq!(split_csv(&record(1, 5).to_csv())[4], "increment V0 by 0x01 \"ignoring\" carry");
+    }
+}
@@ -1,27 +1,31 @@
-use minifb::{Window, WindowOptions, Key};
+use minifb::{Window, WindowOptions, KeyRepeat};
 
+use crate::keymap::{Action, Keymap};
 use crate::state::PixelEvent;
 
 pub struct Chip8Window {
     pixels: Vec<u32>,
     width: usize,
     height: usize,
+    pix_width: usize,  // size of an emulated pixel, changes with the display resolution
+    pix_height: usize,
+    palette: [u32; 4], // background, plane 1, plane 2, both planes
     w: Window
 }
   
 impl Chip8Window {
-    const BG_COLOR : u32 =  29 << 8 |  31 << 4 |  38; //R,G,B
-    const FG_COLOR : u32 = 240 << 8 | 255 << 4 | 255;
-    const PIX_HEIGHT: usize = 14;
-    const PIX_WIDTH: usize = 16;
-
-    pub fn new(width_px: usize, height_px: usize) -> Self {
-        let width = width_px * Self::PIX_WIDTH;
-        let height = height_px * Self::PIX_HEIGHT; 
+    /// `pix_size` is the width and height of an emulated pixel at `width_px` x `height_px` resolution
+    pub fn new(width_px: usize, height_px: usize, pix_size: (usize, usize), palette: [u32; 4]) -> Self {
+        let (pix_width, pix_height) = pix_size;
+        let width = width_px * pix_width;
+        let height = height_px * pix_height; 
         Self {
-            pixels: vec![Self::BG_COLOR; width*height],
+            pixels: vec![palette[0]; width*height],
             width,
             height,
+            pix_width,
+            pix_height,
+            palette,
             w: Window::new("chip8-emu", width, height, WindowOptions::default()).expect("window created")
          }
     }
@@ -40,15 +44,20 @@ impl Chip8Window {
     }
 
     pub fn draw_pixel(&mut self, p: &PixelEvent) {
-        if p.clear_all {
-            self.clear()
-        } else {
-            self.draw_rectangle((p.x as usize)*Self::PIX_WIDTH,  (p.y as usize)*Self::PIX_HEIGHT, Self::PIX_WIDTH, Self::PIX_HEIGHT, if p.on { Self::FG_COLOR } else { Self::BG_COLOR } )
+        match *p {
+            PixelEvent::Set { x, y, color } => self.draw_rectangle((x as usize)*self.pix_width, (y as usize)*self.pix_height, self.pix_width, self.pix_height, self.palette[(color & 0x3) as usize]),
+            PixelEvent::ClearAll => self.clear(),
+            PixelEvent::Resize { width, height } => {
+                // the window keeps its size, emulated pixels are rescaled to fill it
+                self.pix_width = (self.width / width as usize).max(1);
+                self.pix_height = (self.height / height as usize).max(1);
+                self.clear()
+            }
         }
     }
 
     fn clear(&mut self) {
-        self.pixels.fill(Self::BG_COLOR);
+        self.pixels.fill(self.palette[0]);
     }
 
     pub fn update_window(&mut self) {
@@ -59,29 +68,24 @@ impl Chip8Window {
         self.w.is_open()
     }
 
-    pub fn check_keypress(&self) -> Option<u8> {
-        let mut okey = 0u8;
-        self.w.get_keys().iter().for_each(|key|
-          okey = match key { // only the last one counts
-              Key::X => 0x1,
-              Key::C => 0x2,
-              Key::V => 0x3,
-              Key::A => 0x4,
-              Key::S => 0x5, 
-              Key::D => 0x6,
-              Key::F => 0x7,
-              Key::Q => 0x8,
-              Key::W => 0x9, 
-              Key::E => 0xA,
-              Key::R => 0xB,
-              Key::Key1 => 0xC,
-              Key::Key2 => 0xD, 
-              Key::Key3 => 0xE,
-              Key::Key4 => 0xF,
-              _ => 0,
-          }
-        );
-        if okey==0u8 {None} else {Some(okey)} 
+    /// keys of the hex keypad held down in the window, bit k is key k
+    pub fn keypad_state(&self, keymap: &Keymap) -> u16 {
+        self.w.get_keys().iter()
+            .filter_map(|key| keymap.hex_key(&format!("{:?}", key)))
+            .fold(0, |pressed, k| pressed | 1 << k)
+    }
+
+    /// actions whose keys are held down
+    pub fn held_actions(&self, keymap: &Keymap) -> Vec<Action> {
+        self.w.get_keys().iter()
+            .filter_map(|key| keymap.action(&format!("{:?}", key)))
+            .collect()
     }
 
+    /// actions whose keys went down since the last update
+    pub fn actions(&self, keymap: &Keymap) -> Vec<Action> {
+        self.w.get_keys_pressed(KeyRepeat::No).iter()
+            .filter_map(|key| keymap.action(&format!("{:?}", key)))
+            .collect()
+    }
 }
@@ -0,0 +1,81 @@
+//! The assembler against the decoder and the disassembler, which share its opcode table.
+
+use std::fs;
+
+use chip8_emu::{assemble, disassemble, encoding_of, mnemonic};
+
+#[test]
+fn every_opcode_assembles_from_its_mnemonic() {
+    for opcode in 0..=0xffffu16 {
+        if encoding_of(opcode).is_none() { continue }
+        let text = mnemonic(opcode, 0x1234, |a| format!("{:#05x}", a)).unwrap();
+        let mut expected = opcode.to_be_bytes().to_vec();
+        if opcode == 0xf000 { expected.extend_from_slice(&[0x12, 0x34]) }
+        let rom = assemble(&format!("    {}\n", text), "opcode").unwrap_or_else(|e| panic!("{:04x} {}: {}", opcode, text, e)).rom;
+        assert_eq!(rom, expected, "{:04x} {}", opcode, text);
+    }
+}
+
+/// the ROMs in `tests/fixtures` were compiled from the `.8o` sources next to them
+#[test]
+fn disassembled_roms_assemble_into_the_same_bytes() {
+    let mut checked = 0;
+    for entry in fs::read_dir("tests/fixtures").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map(|e| e != "ch8").unwrap_or(true) { continue }
+        let rom = fs::read(&path).unwrap();
+        let source = disassemble(&rom, 0x200).to_string();
+        let assembly = assemble(&source, &path.display().to_string()).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+        assert_eq!(assembly.rom, rom, "{}", path.display());
+        checked += 1;
+    }
+    assert!(checked >= 2, "only {} fixture ROMs in tests/fixtures", checked);
+}
+
+#[test]
+fn labels_constants_and_expressions() {
+    let source = "\
+SPEED = 2 * (3 + 1)     ; forward references are fine
+start:  LD V0, SPEED
+        LD I, sprite
+        ADD V1, -1
+        JP end
+sprite: db 0b00111100, 'A', \"hi\"
+        dw end - start, $
+end     equ sprite + 5 + 4 + 2
+";
+    let assembly = assemble(source, "test.asm").unwrap();
+    assert_eq!(assembly.rom, [0x60, 0x08, 0xa2, 0x08, 0x71, 0xff, 0x12, 0x13,
+                              0x3c, 0x41, 0x68, 0x69, 0x00, 0x13, 0x02, 0x0c]);
+    assert_eq!(assembly.symbol_map(), "0x0008 SPEED\n0x0200 start\n0x0208 sprite\n0x0213 end\n");
+}
+
+#[test]
+fn errors_point_at_line_and_column() {
+    let error = |source: &str| assemble(source, "bad.asm").unwrap_err().to_string();
+    assert_eq!(error("CLS\n  LD V0, missing"), "bad.asm:2:10: undefined symbol missing");
+    assert_eq!(error("  LD V0, 0x100"), "bad.asm:1:10: 256 does not fit into a byte");
+    assert_eq!(error("  LD V0, 1 +"), "bad.asm:1:13: missing value");
+    assert_eq!(error("  LD DT, 3"), "bad.asm:1:3: LD does not take these operands");
+    assert_eq!(error("  db \"abc\n  CLS"), "bad.asm:1:6: unterminated string");
+    assert_eq!(error("  db \""), "bad.asm:1:6: unterminated string");
+    assert_eq!(error("  db \"ab\"c"), "bad.asm:1:10: unexpected text after the string");
+    assert_eq!(error("  FOO"), "bad.asm:1:3: unknown instruction FOO");
+    assert_eq!(error("a: CLS\na: CLS"), "bad.asm:2:1: a is already defined at bad.asm:1:1");
+    assert_eq!(error("A = B\nB = A + 1\nLD V0, A"), "bad.asm:2:5: A is defined in terms of itself");
+}
+
+#[test]
+fn includes_are_relative_to_the_including_file() {
+    let dir = std::env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("main.asm"), "include \"lib/sprites.asm\"\nLD I, ball\n").unwrap();
+    fs::write(dir.join("lib/sprites.asm"), "ball: db 0x80\ninclude \"loop.asm\"\n").unwrap();
+    fs::write(dir.join("lib/loop.asm"), "include \"sprites.asm\"\n").unwrap();
+    let error = chip8_emu::assemble_file(dir.join("main.asm").to_str().unwrap()).unwrap_err();
+    assert!(error.message.ends_with("includes itself"), "{}", error);
+    fs::write(dir.join("lib/loop.asm"), "db 0x40\n").unwrap();
+    let assembly = chip8_emu::assemble_file(dir.join("main.asm").to_str().unwrap()).unwrap();
+    assert_eq!(assembly.rom, [0x80, 0x40, 0xa2, 0x00]);
+    fs::remove_dir_all(dir).unwrap();
+}
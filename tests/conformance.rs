@@ -0,0 +1,95 @@
+//! Runs the test ROMs listed in `tests/conformance.txt` and compares their final display.
+
+use std::fs;
+use std::path::Path;
+
+use chip8_emu::{image, Cartridge, Chip8State, KeyScript, Quirks, StepOutcome, Xorshift};
+
+const EXPECTATIONS: &str = "tests/conformance.txt";
+const CYCLES_PER_FRAME: u32 = 1000;
+
+struct Case {
+    line: usize,
+    rom: String,
+    profile: String,
+    frames: u32,
+    poke: Option<(u16, u8)>,
+    input: Option<String>,
+    hash: Option<u64>
+}
+
+fn parse(text: &str) -> Vec<Case> {
+    text.lines().enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+        .map(|(n, l)| {
+            let cols: Vec<&str> = l.split_whitespace().collect();
+            assert_eq!(cols.len(), 6, "{}:{}: expected rom profile frames poke input hash", EXPECTATIONS, n + 1);
+            let poke = cols[3].split_once('=').map(|(address, val)| (
+                u16::from_str_radix(address.trim_start_matches("0x"), 16).expect("poke address"),
+                u8::from_str_radix(val.trim_start_matches("0x"), 16).expect("poke value")));
+            Case { line: n + 1, rom: cols[0].to_string(), profile: cols[1].to_string(),
+                frames: cols[2].parse().expect("frames"), poke,
+                input: if cols[4] == "-" { None } else { Some(cols[4].to_string()) },
+                hash: if cols[5] == "?" { None } else { Some(u64::from_str_radix(cols[5], 16).expect("hash")) } }
+        })
+        .collect()
+}
+
+/// the hash of the display after `case.frames` frames, with the same frame rules as `chip8-emu headless`
+fn run(case: &Case, rom: &[u8]) -> u64 {
+    let quirks = Quirks::from_name(&case.profile).expect("known quirk profile");
+    let mut cartridge = Cartridge::from_rom(rom, quirks.memory_size).expect("ROM fits");
+    if let Some((address, val)) = case.poke {
+        cartridge.set_memory(address, val).expect("poke inside memory");
+    }
+    let script = case.input.as_ref().map(|f| KeyScript::load(&format!("tests/input/{}", f)).expect("input script"));
+
+    let mut cas = Chip8State::new(cartridge, quirks);
+    cas.set_random(Box::new(Xorshift::new(1)));
+    'frames: for frame in 0..case.frames {
+        cas.keyboard.set_state(script.as_ref().map(|s| s.keys_at(frame)).unwrap_or(0));
+        for _ in 0..CYCLES_PER_FRAME {
+            match cas.step() {
+                Ok(StepOutcome::WaitingForVblank) => break,
+                Ok(StepOutcome::Exit) => break 'frames,
+                Ok(_) => {},
+                Err(e) => panic!("{} with {}: {}", case.rom, case.profile, e)
+            }
+        }
+        cas.tick();
+    }
+    image::framebuffer_hash(&cas.display)
+}
+
+#[test]
+fn test_roms() {
+    let text = fs::read_to_string(EXPECTATIONS).expect("expectations");
+    let bless = std::env::var_os("CHIP8_BLESS").is_some();
+    let mut blessed = text.lines().map(|l| l.to_string()).collect::<Vec<String>>();
+    let mut failures = Vec::new();
+
+    for case in parse(&text) {
+        let path = Path::new("tests/roms").join(&case.rom);
+        let rom = match fs::read(&path) {
+            Ok(rom) => rom,
+            Err(e) => { failures.push(format!("{}:{}: cannot read {}: {}", EXPECTATIONS, case.line, path.display(), e)); continue }
+        };
+        let hash = run(&case, &rom);
+        if bless {
+            let line = &mut blessed[case.line - 1];
+            let keep = line.trim_end().rsplit_once(char::is_whitespace).map(|(head, _)| head.to_string()).unwrap_or_default();
+            *line = format!("{} {:016x}", keep, hash);
+        } else {
+            match case.hash {
+                Some(expected) if expected != hash => failures.push(format!("{} with {}: display hash {:016x}, expected {:016x}", case.rom, case.profile, hash, expected)),
+                Some(_) => {},
+                None => failures.push(format!("{} with {}: display hash {:016x} is not recorded yet", case.rom, case.profile, hash))
+            }
+        }
+    }
+
+    if bless {
+        fs::write(EXPECTATIONS, blessed.join("\n") + "\n").expect("expectations written");
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
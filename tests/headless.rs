@@ -0,0 +1,52 @@
+//! Runs the `chip8-emu` binary without a window.
+
+use std::fs;
+use std::process::Command;
+
+/// runs the binary in a directory of its own holding `files`, and returns its stdout and stderr
+fn chip8_emu(name: &str, files: &[(&str, &[u8])], runs: &[&[&str]]) -> Vec<(String, String)> {
+    let dir = std::env::temp_dir().join(format!("chip8-emu-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (file, contents) in files {
+        fs::write(dir.join(file), contents).unwrap();
+    }
+    let outputs = runs.iter().map(|args| {
+        let output = Command::new(env!("CARGO_BIN_EXE_chip8-emu")).args(*args).current_dir(&dir).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+    }).collect();
+    fs::remove_dir_all(&dir).unwrap();
+    outputs
+}
+
+/// the first frame in which the jsonl trace of `rom` with `input` reaches `pc`
+fn frame_reaching(name: &str, rom: &[u8], input: &str, pc: u16) -> Option<u64> {
+    let outputs = chip8_emu(name, &[("rom.ch8", rom), ("keys.txt", input.as_bytes())],
+        &[&["trace", "rom.ch8", "--format", "jsonl", "--frames", "40", "--input", "keys.txt"]]);
+    outputs[0].0.lines()
+        .find(|line| line.contains(&format!("\"pc\":{},", pc)))
+        .map(|line| {
+            let frame = line.split("\"frame\":").nth(1).unwrap();
+            frame[..frame.find(',').unwrap()].parse().unwrap()
+        })
+}
+
+#[test]
+fn scripted_keys_are_held_from_the_first_instruction_of_their_frame() {
+    // V0 = 5, then skip out of the loop once key 5 is held
+    let rom = [0x60, 0x05, 0xe0, 0x9e, 0x12, 0x02, 0x12, 0x06];
+    assert_eq!(frame_reaching("ex9e", &rom, "30 5\n", 0x206), Some(30));
+    assert_eq!(frame_reaching("ex9e-start", &rom, "0 5\n", 0x206), Some(0));
+    // FX0A takes the key once it is released again
+    let rom = [0xf0, 0x0a, 0x12, 0x02];
+    assert_eq!(frame_reaching("fx0a", &rom, "30 a\n31 -\n", 0x202), Some(31));
+}
+
+#[test]
+fn replays_run_with_the_recorded_font() {
+    let rom = [0x12, 0x00];
+    let outputs = chip8_emu("movie-font", &[("rom.ch8", &rom)], &[
+        &["headless", "rom.ch8", "--frames", "130", "--font", "vip", "--font-address", "0x50", "--record", "run.c8m"],
+        &["headless", "rom.ch8", "--replay", "run.c8m"]]);
+    assert_eq!(outputs[1].1, "");
+}
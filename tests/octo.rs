@@ -0,0 +1,96 @@
+//! Octo programs compiled and run, and the compiler's diagnostics.
+
+use chip8_emu::octo::compile;
+use chip8_emu::{Cartridge, Chip8State, Quirks, StepOutcome};
+
+/// runs the program until it jumps to itself
+fn run(source: &str) -> Chip8State {
+    let rom = compile(source, "test.8o").unwrap_or_else(|e| panic!("{}", e)).rom;
+    let quirks = Quirks::xo_chip();
+    let mut cas = Chip8State::new(Cartridge::from_rom(&rom, quirks.memory_size).unwrap(), quirks);
+    for _ in 0..10_000 {
+        let pc = cas.pc;
+        if cas.step().unwrap() == StepOutcome::WaitingForVblank { cas.tick() }
+        if cas.pc == pc { return cas }
+    }
+    panic!("the program does not halt")
+}
+
+#[test]
+fn compiles_statements_to_their_opcodes() {
+    let source = "
+: main
+    clear
+    v3 := 0x10   v3 += v4   v3 -= 1   v2 := random 0x0f
+    i := ball  sprite v0 v1 8   i := long ball
+    :unpack 0xA ball
+    if v3 == 2 then return
+    helper
+: helper ;
+: ball 0b00111100 0x42
+";
+    let rom = compile(source, "test.8o").unwrap().rom;
+    assert_eq!(rom, [0x00, 0xe0, 0x63, 0x10, 0x83, 0x44, 0x73, 0xff, 0xc2, 0x0f,
+                     0xa2, 0x1e, 0xd0, 0x18, 0xf0, 0x00, 0x02, 0x1e,
+                     0x60, 0xa2, 0x61, 0x1e, 0x43, 0x02, 0x00, 0xee, 0x22, 0x1c, 0x00, 0xee,
+                     0x3c, 0x42]);
+}
+
+#[test]
+fn control_flow_and_comparisons() {
+    let source = "
+:const LIMIT 10
+:alias count v1
+:macro add-twice reg amount { reg += amount reg += amount }
+: main
+    # count to LIMIT, summing v2 < 5 into v3 and the rest into v4
+    loop
+        while count != LIMIT
+        if count < 5 begin
+            v3 += 1
+        else
+            v4 += 1
+        end
+        if count >= 8 then v5 += 1
+        if count > 6 then v6 += 1
+        if count <= 1 then v8 += 1
+        add-twice v7 1
+        count += 1
+    again
+: done
+    jump done
+";
+    let cas = run(source);
+    assert_eq!((cas.v(1), cas.v(3), cas.v(4), cas.v(5), cas.v(6), cas.v(7), cas.v(8)), (10, 5, 5, 2, 3, 20, 2));
+}
+
+#[test]
+fn main_does_not_have_to_come_first() {
+    let source = "
+:calc DOUBLE { 2 * 0x21 }
+: data :byte { DOUBLE + 1 }
+: main
+    i := data
+    load v0
+    i := target
+    save v0      # the operand of the next instruction
+    :next target v1 := 0
+: halt jump halt
+";
+    let cas = run(source);
+    assert_eq!((cas.v(0), cas.v(1)), (0x43, 0x43));
+    assert_eq!(compile(source, "test.8o").unwrap().symbols["DOUBLE"], 0x42);
+}
+
+#[test]
+fn errors_point_at_the_source() {
+    let error = |source: &str| compile(source, "bad.8o").unwrap_err().to_string();
+    assert_eq!(error(": main\n  v0 := 0x100"), "bad.8o:2:9: 256 does not fit into a byte");
+    assert_eq!(error(": main\n  jump nowhere"), "bad.8o:2:8: undefined name nowhere");
+    assert_eq!(error(": main\n  loop\n    v0 += 1"), "bad.8o:2:3: loop has no again");
+    assert_eq!(error(": main\n  else"), "bad.8o:2:3: else without if ... begin");
+    assert_eq!(error(": main\n  if v0 == 1 then"), "bad.8o:2:18: expected a statement but the source ends");
+    assert_eq!(error(":macro m { v0 := x }\n: main m"), "bad.8o:1:18: undefined name x");
+    assert_eq!(error("  clear"), "bad.8o:1:1: the program has no : main to start at");
+    assert_eq!(error(": main\n: main"), "bad.8o:2:3: main is already defined at bad.8o:1:3");
+}
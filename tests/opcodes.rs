@@ -0,0 +1,182 @@
+//! Small hand-assembled programs for the opcodes whose behaviour depends on the quirk profile.
+
+use chip8_emu::{image, Access, Cartridge, Chip8State, FaultReason, Instruction, Quirks, StepOutcome, Watchpoint, Xorshift};
+
+fn machine(program: &[u16], quirks: Quirks) -> Chip8State {
+    let rom: Vec<u8> = program.iter().flat_map(|op| op.to_be_bytes()).collect();
+    let cartridge = Cartridge::from_rom(&rom, quirks.memory_size).unwrap();
+    let mut cas = Chip8State::new(cartridge, quirks);
+    cas.set_random(Box::new(Xorshift::new(1)));
+    cas
+}
+
+/// runs the whole program, a frame passes whenever DXYN waits for one
+fn run(program: &[u16], quirks: Quirks) -> Chip8State {
+    let mut cas = machine(program, quirks);
+    let end = 0x200 + 2*program.len() as u16;
+    while cas.pc < end {
+        if cas.step().unwrap() == StepOutcome::WaitingForVblank {
+            cas.tick()
+        }
+    }
+    cas
+}
+
+#[test]
+fn shift_source_depends_on_quirks() {
+    let program = [0x6181, 0x8016]; // V1 = 0x81, V0 = V1 >> 1 (vip) or V0 >> 1
+    let vip = run(&program, Quirks::vip());
+    assert_eq!((vip.v(0), vip.v(0xf)), (0x40, 1));
+    let schip = run(&program, Quirks::schip());
+    assert_eq!((schip.v(0), schip.v(0xf)), (0, 0));
+}
+
+#[test]
+fn subtraction_sets_vf_when_there_is_no_borrow() {
+    for quirks in [Quirks::vip(), Quirks::schip()] {
+        let program = [0x6005, 0x6103, 0x8015, 0x6203, 0x6305, 0x8235]; // 5 - 3 and 3 - 5
+        let cas = run(&program, quirks.clone());
+        assert_eq!((cas.v(0), cas.v(2), cas.v(0xf)), (2, 0xfe, 0));
+        let cas = run(&[0x6005, 0x6103, 0x8017, 0x6203, 0x6303, 0x8237], quirks.clone()); // 3 - 5 and 3 - 3
+        assert_eq!((cas.v(0), cas.v(2), cas.v(0xf)), (0xfe, 0, 1));
+        let cas = run(&[0x6f00, 0x6103, 0x8f15], quirks); // VF = 0 - 3 leaves only the flag
+        assert_eq!(cas.v(0xf), 0);
+    }
+}
+
+#[test]
+fn logic_resets_vf_on_the_vip_only() {
+    let program = [0x6f05, 0x6003, 0x6105, 0x8011];
+    assert_eq!(run(&program, Quirks::vip()).v(0xf), 0);
+    assert_eq!(run(&program, Quirks::schip()).v(0xf), 5);
+}
+
+#[test]
+fn jump_with_offset_uses_v0_or_vx() {
+    let program = [0x6002, 0x6304, 0xb300];
+    let mut vip = machine(&program, Quirks::vip());
+    let mut schip = machine(&program, Quirks::schip());
+    for _ in 0..program.len() {
+        vip.step().unwrap();
+        schip.step().unwrap();
+    }
+    assert_eq!(vip.pc, 0x302);
+    assert_eq!(schip.pc, 0x304);
+}
+
+#[test]
+fn load_store_moves_i_by_profile() {
+    let program = [0xa300, 0x6201, 0xf255];
+    assert_eq!(run(&program, Quirks::vip()).i(), 0x303);
+    assert_eq!(run(&program, Quirks::chip48()).i(), 0x302);
+    assert_eq!(run(&program, Quirks::schip()).i(), 0x300);
+}
+
+#[test]
+fn big_sprites_are_eight_wide_in_low_resolution_on_schip() {
+    let mut program = vec![0xa206, 0x6000, 0xd000];
+    program.extend([0xffff; 16]);
+    for (quirks, width) in [(Quirks::schip(), 8), (Quirks::xo_chip(), 16)] {
+        let mut cas = machine(&program, quirks);
+        (0..3).for_each(|_| { cas.step().unwrap(); });
+        assert_eq!((cas.display.pixel(width - 1, 15), cas.display.pixel(width, 0)), (1, 0));
+    }
+}
+
+#[test]
+fn drawing_twice_erases_and_sets_the_collision_flag() {
+    let program = [0x6000, 0xf029, 0xd005, 0xd005];
+    let cas = run(&program, Quirks::vip());
+    assert_eq!(cas.v(0xf), 1);
+    assert_eq!(image::framebuffer_hash(&cas.display), image::framebuffer_hash(&run(&[], Quirks::vip()).display));
+}
+
+#[test]
+fn sprites_clip_or_wrap_at_the_edge() {
+    let program = [0x603e, 0x6100, 0xf129, 0xd015]; // the top row of "0" is four pixels wide, drawn at x = 62
+    assert_eq!(run(&program, Quirks::vip()).display.pixel(0, 0), 0);
+    assert_eq!(run(&program, Quirks::xo_chip()).display.pixel(0, 0), 1);
+}
+
+#[test]
+fn bcd_stores_three_digits() {
+    let cas = run(&[0x60fe, 0xa300, 0xf033], Quirks::vip());
+    let digits: Vec<u8> = (0x300..0x303).map(|a| cas.cartridge.get_memory(a).unwrap()).collect();
+    assert_eq!(digits, [2, 5, 4]);
+}
+
+#[test]
+fn return_without_call_faults() {
+    let mut cas = machine(&[0x00ee], Quirks::vip());
+    let e = cas.step().unwrap_err();
+    assert_eq!((e.pc, e.opcode, e.reason), (0x200, Some(0x00ee), FaultReason::StackUnderflow));
+    let e = cas.run_instruction(Instruction::ReturnFromSubroutine).unwrap_err();
+    assert_eq!((e.pc, e.opcode), (0x200, None));
+}
+
+#[test]
+fn waiting_at_the_end_of_memory_stays_there() {
+    let mut cas = machine(&[], Quirks::xo_chip());
+    cas.cartridge.set_memory(0xfffe, 0xf0).unwrap();
+    cas.cartridge.set_memory(0xffff, 0x0a).unwrap();
+    cas.pc = 0xfffe;
+    assert_eq!(cas.step().unwrap(), StepOutcome::WaitingForKey);
+    assert_eq!(cas.pc, 0xfffe);
+    cas.cartridge.set_memory(0xfffe, 0xd0).unwrap();
+    cas.cartridge.set_memory(0xffff, 0x01).unwrap();
+    cas.quirks.display_wait = true;
+    assert_eq!(cas.step().unwrap(), StepOutcome::WaitingForVblank);
+    assert_eq!(cas.pc, 0xfffe);
+}
+
+#[test]
+fn fonts_cannot_overwrite_the_program() {
+    let mut cartridge = Cartridge::from_rom(&[0x00, 0xe0, 0x12, 0x02], Cartridge::MEMORY_SIZE).unwrap();
+    let font = chip8_emu::Font::default();
+    assert!(cartridge.load_font(&font, 0x050).is_ok());
+    assert!(cartridge.load_font(&font, 0x204).is_ok());
+    assert!(cartridge.load_font(&font, 0x200).unwrap_err().contains("overlaps the program at 0x200-0x203"));
+    assert!(cartridge.load_font(&font, 0x1f0).is_err());
+    assert!(cartridge.load_font(&font, 0xfc0).is_err());
+}
+
+#[test]
+fn key_skips_test_the_held_keys() {
+    let program = [0x6007, 0xe09e, 0x6101, 0x6202]; // skip V1 = 1 if key 7 is held
+    let mut cas = machine(&program, Quirks::vip());
+    cas.keyboard.set_key(7, true);
+    for _ in 0..3 {
+        cas.step().unwrap();
+    }
+    assert_eq!((cas.v(1), cas.v(2)), (0, 2));
+}
+
+#[test]
+fn save_states_resume_where_they_were_taken() {
+    let program = [0xc0ff, 0xf029, 0xd125, 0x7105, 0x1200];
+    let mut original = machine(&program, Quirks::vip());
+    for _ in 0..50 {
+        if original.step().unwrap() == StepOutcome::WaitingForVblank { original.tick() }
+    }
+    let mut restored = machine(&[], Quirks::vip());
+    restored.load_state(&original.save_state()).unwrap();
+    for cas in [&mut original, &mut restored] {
+        for _ in 0..50 {
+            if cas.step().unwrap() == StepOutcome::WaitingForVblank { cas.tick() }
+        }
+    }
+    assert_eq!(original.save_state(), restored.save_state());
+}
+
+#[test]
+fn watchpoints_report_the_accessing_instruction() {
+    let program = [0x60fe, 0xa300, 0xf033, 0xf265]; // BCD to 0x300, then read it back
+    let mut cas = machine(&program, Quirks::vip());
+    cas.cartridge.add_watchpoint(Watchpoint::parse("rw:0x301").unwrap());
+    cas.cartridge.add_watchpoint(Watchpoint::parse("x:0x202").unwrap());
+    for _ in 0..program.len() {
+        cas.step().unwrap();
+    }
+    let hits: Vec<(u16, Access, u8, u8)> = cas.take_watch_hits().iter().map(|h| (h.pc, h.access, h.old, h.new)).collect();
+    assert_eq!(hits, [(0x202, Access::Execute, 0xa3, 0xa3), (0x204, Access::Write, 0, 5), (0x206, Access::Read, 5, 5)]);
+}